@@ -1,7 +1,11 @@
 use custom_error::custom_error;
 use io::Read;
 use serde_derive::Deserialize;
-use std::{fs::File, io, path::Path};
+use std::{
+    fs::{read_to_string, File},
+    io,
+    path::Path,
+};
 
 #[derive(PartialEq, Debug, Deserialize)]
 pub(crate) struct BorsConfig {
@@ -9,15 +13,162 @@ pub(crate) struct BorsConfig {
 }
 
 custom_error! {pub Error
-               IOError{source: io::Error} = "could not read bors-ng config",
+               IO{source: io::Error} = "could not read bors-ng config",
                Toml{source: toml::de::Error} = "could not parse bors-ng config as TOML",
-               BadCircleStatusCheck{name: String} = "Bad status check {:?}: Use \"continuous_integration\"",
-               MissingCircleStatusCheck{name: String} = "Missing status check {:?}",
+               BadStatusCheck{name: String, expected: String} = "Bad status check {name:?}: Use {expected:?}",
+               MissingStatusCheck{name: String} = "Missing status check {name:?}",
+               TomlEdit{source: toml_edit::TomlError} = "could not parse bors-ng config for editing",
+               BadStatusList = "the status setting in bors-ng config is not a list",
 }
 
-pub(crate) fn config(root: &Path) -> Result<BorsConfig, Error> {
-    let mut f = File::open(root.join("bors.toml"))?;
+/// The status check of the CircleCI `continuous_integration` workflow.
+pub(crate) const CIRCLECI_WORKFLOW_STATUS: &str = "continuous_integration";
+
+/// The status check of the CircleCI `ci_success` job.
+pub(crate) const CIRCLECI_CI_SUCCESS_STATUS: &str = "ci/circleci: ci_success";
+
+/// The status check of a travis build.
+pub(crate) const TRAVIS_STATUS: &str = "continuous-integration/travis-ci/push";
+
+/// All status checks that the generated configs can produce.
+const KNOWN_STATUSES: &[&str] = &[
+    CIRCLECI_WORKFLOW_STATUS,
+    CIRCLECI_CI_SUCCESS_STATUS,
+    TRAVIS_STATUS,
+];
+
+/// Reads the bors.toml in the repo root, if one exists.
+pub(crate) fn config(root: &Path) -> Result<Option<BorsConfig>, Error> {
+    let path = root.join("bors.toml");
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let mut f = File::open(path)?;
     let mut buf = String::new();
     f.read_to_string(&mut buf)?;
-    Ok(toml::from_str(&buf)?)
+    Ok(Some(toml::from_str(&buf)?))
+}
+
+/// Checks that bors (if it is configured) waits for the `expected`
+/// status check, and for none of the `bad` ones.
+pub(crate) fn verify_status(root: &Path, expected: &str, bad: &[&str]) -> Result<(), Error> {
+    let bors_cfg = match config(root)? {
+        Some(bors_cfg) => bors_cfg,
+        None => return Ok(()),
+    };
+    if let Some(name) = bors_cfg
+        .status
+        .iter()
+        .find(|&el| bad.contains(&el.as_str()))
+    {
+        return Err(Error::BadStatusCheck {
+            name: name.to_string(),
+            expected: expected.to_string(),
+        });
+    }
+    if !bors_cfg.status.iter().any(|el| el == expected) {
+        return Err(Error::MissingStatusCheck {
+            name: expected.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Returns the contents of the bors.toml in the repo root, created or
+/// updated so that bors waits for the `expected` status check instead
+/// of any other status check of a generated config. Other status
+/// checks and settings are kept; `timeout_sec` is only set if a
+/// timeout is given.
+pub(crate) fn with_status(
+    root: &Path,
+    expected: &str,
+    timeout_sec: Option<u64>,
+) -> Result<String, Error> {
+    let path = root.join("bors.toml");
+    let mut doc: toml_edit::Document = if path.is_file() {
+        read_to_string(&path)?.parse()?
+    } else {
+        toml_edit::Document::new()
+    };
+    if !doc.contains_key("status") {
+        doc["status"] = toml_edit::value(toml_edit::Array::new());
+    }
+    let status = doc["status"].as_array_mut().ok_or(Error::BadStatusList)?;
+    let multiline = status.iter().any(|el| {
+        el.decor()
+            .prefix()
+            .and_then(|prefix| prefix.as_str())
+            .is_some_and(|prefix| prefix.contains('\n'))
+    });
+    let before = status.to_string();
+    status.retain(|el| match el.as_str() {
+        Some(name) => name == expected || !KNOWN_STATUSES.contains(&name),
+        None => true,
+    });
+    if !status.iter().any(|el| el.as_str() == Some(expected)) {
+        status.push(expected);
+    }
+    if status.to_string() != before {
+        // Lay out the edited list like the original one:
+        status.fmt();
+        if multiline {
+            for el in status.iter_mut() {
+                el.decor_mut().set_prefix("\n  ");
+            }
+            status.set_trailing_comma(true);
+            status.set_trailing("\n");
+        }
+    }
+    if let Some(timeout_sec) = timeout_sec {
+        doc["timeout_sec"] = toml_edit::value(timeout_sec as i64);
+    }
+    Ok(doc.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+
+    #[test]
+    fn update_existing_config() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("bors.toml");
+        write(
+            &path,
+            r#"# Keep this comment
+status = [
+  "some/other-check",
+  "continuous_integration",
+]
+timeout_sec = 300
+delete_merged_branches = true
+"#,
+        )?;
+        write(&path, with_status(tmp.path(), TRAVIS_STATUS, None)?)?;
+        assert_eq!(
+            read_to_string(&path)?,
+            r#"# Keep this comment
+status = [
+  "some/other-check",
+  "continuous-integration/travis-ci/push",
+]
+timeout_sec = 300
+delete_merged_branches = true
+"#
+        );
+        verify_status(tmp.path(), TRAVIS_STATUS, &[])?;
+        Ok(())
+    }
+
+    #[test]
+    fn create_config() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        assert_eq!(config(tmp.path())?, None);
+        assert_eq!(
+            with_status(tmp.path(), CIRCLECI_WORKFLOW_STATUS, Some(900))?,
+            "status = [\"continuous_integration\"]\ntimeout_sec = 900\n"
+        );
+        Ok(())
+    }
 }
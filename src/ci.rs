@@ -1,4 +1,3 @@
-use askama;
 use std::env::current_dir;
 use std::fs::create_dir_all;
 use std::io;
@@ -7,14 +6,16 @@ use std::path::{Path, PathBuf};
 
 use custom_error::custom_error;
 
+use crate::bors;
+
 pub(crate) mod circleci;
 pub(crate) mod travis;
 
 custom_error! {pub Error
-               TemplateError{source: askama::Error} = "could not render template",
-               IOError{source: io::Error} = "could not write to CI config",
-               PersistError{source: tempfile::PersistError} = "could not overwrite",
-               BorsConfig{source: crate::bors::Error} = "Could not validate bors-ng config: {}",
+               Template{source: askama::Error} = "could not render template",
+               IO{source: io::Error} = "could not write to CI config",
+               Persist{source: tempfile::PersistError} = "could not overwrite",
+               BorsConfig{source: crate::bors::Error} = "Could not validate bors-ng config: {source}",
 }
 
 pub(crate) trait CISystem: askama::Template {
@@ -22,48 +23,88 @@ pub(crate) trait CISystem: askama::Template {
     fn write_preamble(&self, output: impl io::Write) -> Result<(), Error>;
 
     /// Renders the CI system template and writes it to either the
-    /// given config file or to the default location.
+    /// given config file or to the default location. Nothing gets
+    /// written if the generated config would not result in working CI.
     fn render_into_config_file(&self, root: &Path) -> Result<(), Error> {
-        let dest = self.config_file_name(root);
-        let dest_dir = match dest.parent() {
-            Some(dir) => dir.to_path_buf(),
-            None => current_dir()?,
-        };
-        create_dir_all(&dest_dir)?;
-        let output = tempfile::NamedTempFile::new_in(dest_dir)?;
-
-        self.write_preamble(&output)?;
-        writeln!(&output, "{}", self.render()?)?;
-        output.persist(dest)?;
         self.validate_config(root)?;
+        for (dest, contents) in self.render_files(root)? {
+            write_file(&dest, &contents)?;
+        }
+        Ok(())
+    }
+
+    /// Like `render_into_config_file`, but creates or updates the
+    /// bors.toml in the repo root to wait for this CI system's status
+    /// check instead of validating it. Files only get written once
+    /// everything rendered.
+    fn render_with_bors_config(&self, root: &Path, timeout_sec: Option<u64>) -> Result<(), Error> {
+        let files = self.render_files(root)?;
+        let bors_config = bors::with_status(root, self.bors_status(), timeout_sec)?;
+        write_file(&root.join("bors.toml"), bors_config.as_bytes())?;
+        for (dest, contents) in files {
+            write_file(&dest, &contents)?;
+        }
         Ok(())
     }
 
+    /// Returns the config file and all additional files (and their
+    /// contents) that the CI system needs.
+    fn render_files(&self, root: &Path) -> Result<Vec<(PathBuf, Vec<u8>)>, Error> {
+        let mut contents = vec![];
+        self.write_preamble(&mut contents)?;
+        writeln!(&mut contents, "{}", self.render()?)?;
+        let mut files = vec![(self.config_file_name(root), contents)];
+        for (dest, contents) in self.additional_files(root)? {
+            files.push((dest, contents.into_bytes()));
+        }
+        Ok(files)
+    }
+
+    /// Returns any other files (and their contents) that the CI
+    /// system needs next to the main config file.
+    fn additional_files(&self, _root: &Path) -> Result<Vec<(PathBuf, String)>, Error> {
+        Ok(vec![])
+    }
+
     /// Checks that the configuration that was generated will result
     /// in working CI.
     fn validate_config(&self, _root: &Path) -> Result<(), Error> {
         Ok(())
     }
 
+    /// Returns the status check that bors-ng should wait for.
+    fn bors_status(&self) -> &'static str;
+
     /// Returns a configuration file name from the root of the repo.
     fn config_file_name(&self, root: &Path) -> PathBuf;
 }
 
+/// Atomically replaces the file at `dest` with `contents`.
+fn write_file(dest: &Path, contents: &[u8]) -> Result<(), Error> {
+    let dest_dir = match dest.parent() {
+        Some(dir) => dir.to_path_buf(),
+        None => current_dir()?,
+    };
+    create_dir_all(&dest_dir)?;
+    let mut output = tempfile::NamedTempFile::new_in(dest_dir)?;
+    output.write_all(contents)?;
+    output.persist(dest)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::CISystem;
-    use askama;
     use custom_error::custom_error;
     use std::fmt;
     use std::fs;
     use std::io;
-    use tempfile;
 
     custom_error! {Error
                    IO{source: io::Error} = "IO",
                    Fmt{source: fmt::Error} = "fmt",
                    Tempfile{source: tempfile::PersistError} = "Test setup/teardown",
-                   CIError{source: super::Error} = "error from the CI config mechanics",
+                   CI{source: super::Error} = "error from the CI config mechanics",
     }
 
     struct NonSystem {}
@@ -82,6 +123,9 @@ mod tests {
         fn write_preamble(&self, _output: impl io::Write) -> Result<(), super::Error> {
             Ok(())
         }
+        fn bors_status(&self) -> &'static str {
+            "none"
+        }
         fn config_file_name(&self, root: &std::path::Path) -> std::path::PathBuf {
             root.join("does_not_exist.tmp")
         }
@@ -120,4 +164,18 @@ mod tests {
             Ok(())
         }
     }
+
+    #[test]
+    fn with_bors_config() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let sys = NonSystem {};
+        let path = dir.path();
+        sys.render_with_bors_config(path, Some(60))?;
+        assert!(path.join("does_not_exist.tmp").is_file());
+        assert_eq!(
+            fs::read_to_string(path.join("bors.toml"))?,
+            "status = [\"none\"]\ntimeout_sec = 60\n"
+        );
+        Ok(())
+    }
 }
@@ -1,28 +1,164 @@
 use serde_derive::Serialize;
-use std::{io, path::Path};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
 
 use super::CISystem;
-use crate::config::MatrixEntryExt;
+use crate::config::{BuiltinJob, MatrixEntryExt};
 use crate::{bors, TemplateCIConfig};
 
 use askama::Template;
 
+/// The CircleCI config file, relative to the repo root. With change
+/// detection, it holds the setup config.
+pub(crate) const CONFIG_FILE: &str = ".circleci/config.yml";
+
+/// The config that the setup config continues with.
+const CONTINUE_CONFIG_FILE: &str = ".circleci/continue_config.yml";
+
 #[derive(Template, Debug)]
 #[template(path = "circleci.yml")]
 pub(crate) struct CircleCI {
     conf: TemplateCIConfig,
     filters: Filters,
+
+    /// Filters for jobs that don't run on release tags.
+    branch_filters: Filters,
+}
+
+/// The setup config that decides which workspace members a change
+/// affects, and continues with the main config.
+#[derive(Template, Debug)]
+#[template(path = "circleci_setup.yml")]
+struct CircleCISetup<'a> {
+    conf: &'a TemplateCIConfig,
+    filters: &'a Filters,
 }
 
 impl From<TemplateCIConfig> for CircleCI {
     fn from(conf: TemplateCIConfig) -> Self {
         CircleCI {
             filters: Filters::from_config(&conf),
+            branch_filters: Filters::branches_from_config(&conf),
             conf,
         }
     }
 }
 
+impl CircleCI {
+    /// Returns the names of the jobs in the `continuous_integration`
+    /// workflow that the `ci_success` job waits for: all jobs that
+    /// aren't allowed to fail.
+    fn gating_jobs(&self) -> Vec<String> {
+        let conf = &self.conf;
+        let mut jobs = vec![];
+        for version in conf.test_versions() {
+            for os in &conf.os {
+                jobs.push(format!("test-{}{}", version, conf.os_suffix(os)));
+            }
+        }
+        if conf.pinned_toolchain().is_some() {
+            jobs.push("test_pinned".to_string());
+        }
+        for member in &conf.member_tests {
+            for (version, _) in conf.member_test_versions() {
+                jobs.push(format!("{}-{}", member.job_name(), version));
+            }
+        }
+        for (name, entry) in conf.matrix_entries() {
+            if !entry.run || entry.allow_failure {
+                continue;
+            }
+            for os in conf.entry_oses(entry) {
+                jobs.push(format!("{}{}", name, conf.os_suffix(os)));
+            }
+        }
+        if conf.msrv.is_some() {
+            jobs.push("msrv".to_string());
+        }
+        jobs.extend(conf.targets.iter().map(|target| target.name()));
+        jobs.extend(conf.features.jobs().into_iter().map(|job| job.name));
+        jobs
+    }
+
+    /// Returns the names of the gating jobs that don't run on
+    /// release tags.
+    fn branch_only_jobs(&self) -> Vec<String> {
+        let conf = &self.conf;
+        let mut jobs = vec![];
+        for job in conf.builtin_jobs() {
+            if job.release_tags {
+                continue;
+            }
+            for os in conf.entry_oses(job.entry) {
+                jobs.push(format!("{}{}", job.name, conf.os_suffix(os)));
+            }
+        }
+        jobs
+    }
+
+    /// Returns the filters of the `ci_success` job, which can only
+    /// run on release tags if all gating jobs do.
+    fn ci_success_filters(&self) -> &Filters {
+        if self.branch_only_jobs().is_empty() {
+            &self.filters
+        } else {
+            &self.branch_filters
+        }
+    }
+
+    /// Returns the filters of a built-in job.
+    fn builtin_job_filters(&self, job: &BuiltinJob) -> &Filters {
+        if job.release_tags {
+            &self.filters
+        } else {
+            &self.branch_filters
+        }
+    }
+
+    /// Returns the names of the jobs that the release job waits for:
+    /// the gating jobs that also run on release tags.
+    fn release_requires(&self) -> Vec<String> {
+        let branch_only = self.branch_only_jobs();
+        if self.conf.ci_success && branch_only.is_empty() {
+            vec!["ci_success".to_string()]
+        } else {
+            self.gating_jobs()
+                .into_iter()
+                .filter(|job| !branch_only.contains(job))
+                .collect()
+        }
+    }
+
+    /// Returns the prefix of the cache keys for a job (given by the
+    /// `job` parameter of the caching commands), which changes with
+    /// the toolchain version.
+    fn cache_key_prefix(&self) -> String {
+        let mut prefix = format!(
+            r#"cargo-{}-<< parameters.job >>-{{{{ arch }}}}-{{{{ checksum "/tmp/rust-version" }}}}-"#,
+            self.conf.cache_version
+        );
+        if self.conf.uses_tools() {
+            // Caches get saved only once per key, so the key has to
+            // change with the tools that get installed:
+            prefix += r#"{{ checksum "/tmp/tools" }}-"#;
+        }
+        prefix
+    }
+
+    /// Returns the cached directory that tools get installed into.
+    fn tools_cache_path(&self) -> String {
+        self.conf.tools_dir().replacen("$HOME", "~", 1)
+    }
+
+    /// Returns the cache key for a job, which also changes with the
+    /// contents of `Cargo.lock`.
+    fn cache_key(&self) -> String {
+        self.cache_key_prefix() + r#"{{ checksum "Cargo.lock" }}"#
+    }
+}
+
 impl CISystem for CircleCI {
     fn write_preamble(&self, mut _output: impl io::Write) -> Result<(), super::Error> {
         Ok(())
@@ -30,34 +166,45 @@ impl CISystem for CircleCI {
 
     /// Checks a bors.toml (if it exists) for the correct CI task names.
     fn validate_config(&self, root: &Path) -> Result<(), super::Error> {
-        let bors_cfg = bors::config(root)?;
-        if let Some(name) = bors_cfg
-            .status
-            .iter()
-            .find(|&el| el == "ci/circleci: ci_success")
-        {
-            return Err(bors::Error::BadCircleStatusCheck {
-                name: name.to_string(),
-            }
-            .into());
+        if self.conf.ci_success {
+            bors::verify_status(root, self.bors_status(), &[])?;
+        } else {
+            bors::verify_status(
+                root,
+                self.bors_status(),
+                &[bors::CIRCLECI_CI_SUCCESS_STATUS],
+            )?;
         }
+        Ok(())
+    }
 
-        if bors_cfg
-            .status
-            .iter()
-            .find(|&el| el == "continuous_integration")
-            .is_none()
-        {
-            return Err(bors::Error::MissingCircleStatusCheck {
-                name: "continuous_integration".to_string(),
-            }
-            .into());
+    fn bors_status(&self) -> &'static str {
+        if self.conf.ci_success {
+            bors::CIRCLECI_CI_SUCCESS_STATUS
+        } else {
+            bors::CIRCLECI_WORKFLOW_STATUS
         }
-        Ok(())
     }
 
-    fn config_file_name(&self, root: &Path) -> std::path::PathBuf {
-        root.join(".circleci/config.yml")
+    /// Writes the setup config if the main config is a continuation
+    /// config.
+    fn additional_files(&self, root: &Path) -> Result<Vec<(PathBuf, String)>, super::Error> {
+        if !self.conf.detects_changes() {
+            return Ok(vec![]);
+        }
+        let setup = CircleCISetup {
+            conf: &self.conf,
+            filters: &self.filters,
+        };
+        Ok(vec![(root.join(CONFIG_FILE), setup.render()? + "\n")])
+    }
+
+    fn config_file_name(&self, root: &Path) -> PathBuf {
+        if self.conf.detects_changes() {
+            root.join(CONTINUE_CONFIG_FILE)
+        } else {
+            root.join(CONFIG_FILE)
+        }
     }
 }
 
@@ -73,7 +220,9 @@ pub(crate) struct SpecificFilters {
 #[derive(Serialize, Debug)]
 pub(crate) struct Filters {
     branches: SpecificFilters,
-    tags: SpecificFilters,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<SpecificFilters>,
 }
 
 impl Filters {
@@ -89,10 +238,18 @@ impl Filters {
                     .map(String::from)
                     .collect(),
             },
-            tags: SpecificFilters {
+            tags: Some(SpecificFilters {
                 only: tags.into_iter().map(String::from).collect(),
                 ignore: vec![],
-            },
+            }),
+        }
+    }
+
+    /// Returns the filters for jobs that only run on branches.
+    fn branches_from_config(conf: &TemplateCIConfig) -> Filters {
+        Filters {
+            tags: None,
+            ..Filters::from_config(conf)
         }
     }
 }
@@ -101,9 +258,10 @@ impl Filters {
 mod test {
     use super::super::Error;
     use super::*;
+    use crate::config::MemberTest;
+    use crate::toolchain::{Toolchain, ToolchainFileMode};
     use io::Write;
     use std::{fs::File, io};
-    use tempfile;
 
     #[test]
     fn validate_old_style_bors_config() -> Result<(), Box<dyn std::error::Error>> {
@@ -114,7 +272,33 @@ mod test {
             .write_all("status = [\"ci/circleci: ci_success\"]".as_bytes())?;
         match sys.validate_config(dir) {
             Err(Error::BorsConfig {
-                source: bors::Error::BadCircleStatusCheck { name },
+                source: bors::Error::BadStatusCheck { name, expected },
+            }) => {
+                assert_eq!(name, "ci/circleci: ci_success");
+                assert_eq!(expected, "continuous_integration");
+            }
+            other => {
+                panic!("Expected an error, got {:?}", other);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn validate_ci_success_bors_config() -> Result<(), Box<dyn std::error::Error>> {
+        let sys = CircleCI::from(TemplateCIConfig {
+            ci_success: true,
+            ..Default::default()
+        });
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path();
+        sys.validate_config(dir)?;
+
+        File::create(dir.join("bors.toml"))?
+            .write_all("status = [\"continuous_integration\"]".as_bytes())?;
+        match sys.validate_config(dir) {
+            Err(Error::BorsConfig {
+                source: bors::Error::MissingStatusCheck { name },
             }) => {
                 assert_eq!(name, "ci/circleci: ci_success");
             }
@@ -122,6 +306,176 @@ mod test {
                 panic!("Expected an error, got {:?}", other);
             }
         }
+
+        File::create(dir.join("bors.toml"))?
+            .write_all("status = [\"ci/circleci: ci_success\"]".as_bytes())?;
+        sys.validate_config(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn gating_jobs() {
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+versions = ["stable", "nightly"]
+ci_success = true
+
+[clippy]
+allow_failure = true
+
+[additional_matrix_entries.integration]
+commandline = "cargo test --test integration"
+"#,
+        )
+        .expect("config");
+        let mut sys = CircleCI::from(conf);
+        assert_eq!(
+            sys.gating_jobs(),
+            vec!["test-stable", "test-nightly", "rustfmt", "integration"]
+        );
+        assert_eq!(sys.release_requires(), vec!["ci_success"]);
+        sys.conf.ci_success = false;
+        assert_eq!(sys.release_requires(), sys.gating_jobs());
+    }
+
+    #[test]
+    fn gating_member_jobs_on_pinned_toolchain() {
+        let member_conf = |toolchain_file| TemplateCIConfig {
+            versions: vec!["stable".to_string()],
+            toolchain_file,
+            toolchain: Some(Toolchain {
+                channel: "1.70.0".to_string(),
+                components: vec![],
+                targets: vec![],
+            }),
+            member_tests: vec![MemberTest {
+                name: "foo".to_string(),
+                commandline: "cargo test -p foo".to_string(),
+                dir: "foo".to_string(),
+                has_lib_targets: true,
+                paths: vec!["foo".to_string()],
+            }],
+            ..Default::default()
+        };
+
+        let sys = CircleCI::from(member_conf(ToolchainFileMode::Respect));
+        assert_eq!(
+            sys.gating_jobs(),
+            vec!["test_pinned", "test-foo-1.70.0", "rustfmt", "clippy"]
+        );
+        let sys = CircleCI::from(member_conf(ToolchainFileMode::Matrix));
+        assert_eq!(
+            sys.gating_jobs(),
+            vec![
+                "test-stable",
+                "test_pinned",
+                "test-foo-stable",
+                "test-foo-1.70.0",
+                "rustfmt",
+                "clippy"
+            ]
+        );
+    }
+
+    #[test]
+    fn branch_only_jobs() {
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+ci_success = true
+
+[semver_checks]
+run = true
+"#,
+        )
+        .expect("config");
+        let sys = CircleCI::from(conf);
+        assert_eq!(sys.branch_only_jobs(), vec!["semver_checks"]);
+        assert_eq!(
+            sys.release_requires(),
+            vec!["test-stable", "test-nightly", "rustfmt", "clippy"]
+        );
+        assert!(sys.ci_success_filters().tags.is_none());
+    }
+
+    #[test]
+    fn msrv_job_tools() -> Result<(), Box<dyn std::error::Error>> {
+        let sys = CircleCI::from(TemplateCIConfig {
+            msrv: Some("1.60.0".to_string()),
+            tools: vec!["cargo-deny".to_string()],
+            ..Default::default()
+        });
+        let config = sys.render()?;
+        let msrv_job = &config[config.find("\n  msrv:").expect("msrv job")..];
+        // The tools build on stable, not on the MSRV toolchain:
+        assert!(msrv_job.contains(concat!(
+            "rustup toolchain install stable --profile minimal && ",
+            r#"cargo +stable install --locked --root "$HOME/.cargo-tools" cargo-deny"#
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn cache_keys() {
+        let sys = CircleCI::from(TemplateCIConfig {
+            cache_version: "v2".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(
+            sys.cache_key(),
+            r#"cargo-v2-<< parameters.job >>-{{ arch }}-{{ checksum "/tmp/rust-version" }}-{{ checksum "Cargo.lock" }}"#
+        );
+        assert!(sys.cache_key().starts_with(&sys.cache_key_prefix()));
+    }
+
+    #[test]
+    fn tool_cache() {
+        let sys = CircleCI::from(TemplateCIConfig {
+            tools: vec!["cargo-deny".to_string()],
+            ..Default::default()
+        });
+        assert!(sys
+            .cache_key_prefix()
+            .ends_with(r#"{{ checksum "/tmp/tools" }}-"#));
+        assert_eq!(sys.tools_cache_path(), "~/.cargo-tools");
+    }
+
+    #[test]
+    fn change_detection_files() -> Result<(), Box<dyn std::error::Error>> {
+        let root = Path::new("/repo");
+        let sys = CircleCI::from(TemplateCIConfig::default());
+        assert_eq!(
+            sys.config_file_name(root),
+            root.join(".circleci/config.yml")
+        );
+        assert!(sys.additional_files(root)?.is_empty());
+
+        let mut conf = TemplateCIConfig::default();
+        conf.change_detection.run = true;
+        conf.member_tests.push(MemberTest {
+            name: "foo".to_string(),
+            commandline: "cargo test -p foo".to_string(),
+            dir: "foo".to_string(),
+            has_lib_targets: true,
+            paths: vec!["foo".to_string()],
+        });
+        let sys = CircleCI::from(conf);
+        assert_eq!(
+            sys.config_file_name(root),
+            root.join(".circleci/continue_config.yml")
+        );
+        let files = sys.additional_files(root)?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, root.join(".circleci/config.yml"));
+        assert!(files[0].1.contains("foo/.* run-test-foo true"));
+        // Pushes to master compare against the previous revision:
+        assert!(files[0]
+            .1
+            .contains("base-revision: << pipeline.git.base_revision >>"));
+        // Scheduled builds continue with the scheduled workflow:
+        assert!(files[0].1.contains(r#"parameters: '{"scheduled": true}'"#));
+        let config = sys.render()?;
+        assert!(config.contains("when: << pipeline.parameters.scheduled >>"));
+        assert!(!config.contains("triggers:"));
         Ok(())
     }
 
@@ -133,7 +487,7 @@ mod test {
         File::create(dir.join("bors.toml"))?.write_all("status = [\"welp\"]".as_bytes())?;
         match sys.validate_config(dir) {
             Err(Error::BorsConfig {
-                source: bors::Error::MissingCircleStatusCheck { name },
+                source: bors::Error::MissingStatusCheck { name },
             }) => {
                 assert_eq!(name, "continuous_integration");
             }
@@ -1,11 +1,14 @@
 use std::{io, path::Path};
 
 use super::CISystem;
-use crate::config::MatrixEntryExt;
-use crate::TemplateCIConfig;
+use crate::config::{BuiltinJob, MatrixEntry, MatrixEntryExt};
+use crate::{bors, TemplateCIConfig};
 
 use askama::Template;
 
+/// The travis config file, relative to the repo root.
+pub(crate) const CONFIG_FILE: &str = ".travis.yml";
+
 #[derive(Template, Debug)]
 #[template(path = "travis.yml")]
 pub(crate) struct TravisCI {
@@ -18,12 +21,304 @@ impl From<TemplateCIConfig> for TravisCI {
     }
 }
 
+impl TravisCI {
+    /// Returns the configuration that the travis config got
+    /// generated from.
+    pub(crate) fn into_config(self) -> TemplateCIConfig {
+        self.conf
+    }
+
+    /// Returns the travis `if:` condition for the whole build, which
+    /// restricts cron builds to the scheduled test branches.
+    fn build_condition(&self) -> String {
+        if self.conf.scheduled_test_branches.is_empty() {
+            return "type != cron".to_string();
+        }
+        format!(
+            "type != cron OR branch IN ({})",
+            self.conf.scheduled_test_branches.join(", ")
+        )
+    }
+
+    /// Returns the travis `if:` condition for a matrix entry, or None
+    /// if it should run on every build type.
+    fn entry_condition(&self, entry: &MatrixEntry) -> Option<&'static str> {
+        match (entry.run, entry.run_cron) {
+            (true, true) => None,
+            (false, true) => Some("type = cron"),
+            _ => Some("type != cron"),
+        }
+    }
+
+    /// Returns the travis `if:` condition for a built-in job, which
+    /// also skips release tags for jobs that don't run on them.
+    fn builtin_condition(&self, job: &BuiltinJob) -> Option<String> {
+        let condition = self.entry_condition(job.entry);
+        if job.release_tags {
+            return condition.map(String::from);
+        }
+        Some(match condition {
+            Some(condition) => format!("{} AND tag IS blank", condition),
+            None => "tag IS blank".to_string(),
+        })
+    }
+
+    /// Returns the bash condition that is true in the test jobs,
+    /// including the ones of workspace members.
+    fn test_condition(&self) -> String {
+        let mut vars = vec!["RUN_TEST".to_string()];
+        vars.extend(
+            self.conf
+                .member_tests
+                .iter()
+                .map(|member| format!("RUN_TEST_{}", member.env_name())),
+        );
+        let conditions: Vec<String> = vars
+            .iter()
+            .map(|var| format!(r#""${}" == "true""#, var))
+            .collect();
+        format!("[[ {} ]]", conditions.join(" || "))
+    }
+
+    /// Returns the name of the build stage for a job. The test jobs
+    /// from the build matrix always run in the default `test` stage.
+    fn stage_name(&self, name: &str) -> String {
+        let stage = self.conf.stage(name);
+        if stage == self.conf.stage("test") {
+            "test".to_string()
+        } else {
+            format!("stage-{}", stage + 1)
+        }
+    }
+
+    /// Returns true if the jobs get split into build stages.
+    fn uses_stages(&self) -> bool {
+        self.conf.uses_stages() || self.conf.ci_success || self.conf.release.run
+    }
+
+    /// Returns the names of all build stages, in order. The
+    /// `ci_success` and `release` stages only run once all previous
+    /// stages passed.
+    fn stages(&self) -> Vec<String> {
+        let test_stage = self.conf.stage("test");
+        let mut stages: Vec<String> = (0..=self.conf.last_stage())
+            .map(|stage| {
+                if stage == test_stage {
+                    "test".to_string()
+                } else {
+                    format!("stage-{}", stage + 1)
+                }
+            })
+            .collect();
+        if self.conf.ci_success {
+            stages.push("ci_success".to_string());
+        }
+        if self.conf.release.run {
+            stages.push("release".to_string());
+        }
+        stages
+    }
+
+    /// Returns the environment variable suffix and command line of
+    /// each built-in job that runs a command after succeeding.
+    fn after_success(&self) -> Vec<(String, String)> {
+        self.conf
+            .builtin_jobs()
+            .into_iter()
+            .filter_map(|job| Some((job.env_name(), job.after_success.clone()?)))
+            .collect()
+    }
+
+    /// Returns the anchors of the matrix rows for entries that are
+    /// allowed to fail.
+    fn allowed_failures(&self) -> Vec<String> {
+        let mut anchors = vec![];
+        for (name, entry) in self.conf.matrix_entries() {
+            if !entry.allow_failure || !(entry.run || entry.run_cron) {
+                continue;
+            }
+            let anchor = if self.conf.additional_matrix_entries.contains_key(name) {
+                name.to_string()
+            } else {
+                format!("{}_build", name)
+            };
+            for os in self.conf.entry_oses(entry) {
+                anchors.push(format!("{}{}", anchor, self.conf.os_suffix(os)));
+            }
+        }
+        anchors
+    }
+}
+
 impl CISystem for TravisCI {
     fn write_preamble(&self, mut _output: impl io::Write) -> Result<(), super::Error> {
         Ok(())
     }
 
+    /// Checks a bors.toml (if it exists) for the travis status check.
+    fn validate_config(&self, root: &Path) -> Result<(), super::Error> {
+        bors::verify_status(root, self.bors_status(), &[])?;
+        Ok(())
+    }
+
+    fn bors_status(&self) -> &'static str {
+        bors::TRAVIS_STATUS
+    }
+
     fn config_file_name(&self, root: &Path) -> std::path::PathBuf {
-        root.join(".travis.yml")
+        root.join(CONFIG_FILE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Error;
+    use super::*;
+    use crate::config::CustomEntry;
+
+    fn custom_entry(toml_src: &str) -> CustomEntry {
+        toml::from_str(toml_src).expect("test entry should parse")
+    }
+
+    #[test]
+    fn bors_status_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path();
+        std::fs::write(
+            dir.join("bors.toml"),
+            "status = [\"continuous_integration\"]\n",
+        )?;
+        let sys = TravisCI::from(TemplateCIConfig::default());
+        match sys.render_into_config_file(dir) {
+            Err(Error::BorsConfig {
+                source: bors::Error::MissingStatusCheck { name },
+            }) => assert_eq!(name, bors::TRAVIS_STATUS),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        assert!(!sys.config_file_name(dir).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn entry_conditions() {
+        let sys = TravisCI::from(TemplateCIConfig::default());
+        let both = custom_entry("commandline = 'true'\nrun_cron = true");
+        let cron_only = custom_entry("commandline = 'true'\nrun = false\nrun_cron = true");
+        let push_only = custom_entry("commandline = 'true'");
+        assert_eq!(sys.entry_condition(both.the_entry()), None);
+        assert_eq!(
+            sys.entry_condition(cron_only.the_entry()),
+            Some("type = cron")
+        );
+        assert_eq!(
+            sys.entry_condition(push_only.the_entry()),
+            Some("type != cron")
+        );
+        assert_eq!(
+            sys.entry_condition(sys.conf.clippy.the_entry()),
+            Some("type != cron")
+        );
+    }
+
+    #[test]
+    fn build_condition() {
+        let sys = TravisCI::from(TemplateCIConfig::default());
+        assert_eq!(sys.build_condition(), "type != cron OR branch IN (master)");
+
+        let sys = TravisCI::from(TemplateCIConfig {
+            scheduled_test_branches: vec![],
+            ..Default::default()
+        });
+        assert_eq!(sys.build_condition(), "type != cron");
+    }
+
+    #[test]
+    fn stages() {
+        let mut conf: TemplateCIConfig = toml::from_str(
+            r#"
+test_requires = ["rustfmt"]
+
+[bench]
+run = true
+requires = ["test"]
+"#,
+        )
+        .expect("requirements");
+        conf.validate().expect("valid requirements");
+        let sys = TravisCI::from(conf);
+        assert_eq!(sys.stages(), vec!["stage-1", "test", "stage-3"]);
+        assert_eq!(sys.stage_name("rustfmt"), "stage-1");
+        assert_eq!(sys.stage_name("clippy"), "stage-1");
+        assert_eq!(sys.stage_name("msrv"), "test");
+        assert_eq!(sys.stage_name("bench"), "stage-3");
+    }
+
+    #[test]
+    fn builtin_conditions() {
+        let sys = TravisCI::from(
+            toml::from_str::<TemplateCIConfig>(
+                r#"
+[docs]
+run = true
+run_cron = true
+
+[semver_checks]
+run = true
+"#,
+            )
+            .expect("config"),
+        );
+        let jobs = sys.conf.builtin_jobs();
+        assert_eq!(sys.builtin_condition(&jobs[0]), None);
+        assert_eq!(
+            sys.builtin_condition(&jobs[1]).as_deref(),
+            Some("type != cron AND tag IS blank")
+        );
+    }
+
+    #[test]
+    fn canary() {
+        let sys =
+            TravisCI::from(toml::from_str::<TemplateCIConfig>("canary = true").expect("canary"));
+        let jobs = sys.conf.builtin_jobs();
+        assert_eq!(
+            sys.builtin_condition(&jobs[0]).as_deref(),
+            Some("type = cron AND tag IS blank")
+        );
+        assert_eq!(sys.allowed_failures(), vec!["canary_build"]);
+    }
+
+    #[test]
+    fn release_stage() {
+        let sys = TravisCI::from(
+            toml::from_str::<TemplateCIConfig>("[release]\nrun = true").expect("release"),
+        );
+        assert!(sys.uses_stages());
+        assert_eq!(sys.stages(), vec!["test", "release"]);
+    }
+
+    #[test]
+    fn ci_success() {
+        let sys = TravisCI::from(
+            toml::from_str::<TemplateCIConfig>(
+                r#"
+os = ["linux", "macos"]
+ci_success = true
+
+[clippy]
+allow_failure = true
+os = ["linux", "macos"]
+
+[bench]
+allow_failure = true
+"#,
+            )
+            .expect("config"),
+        );
+        assert_eq!(sys.stages(), vec!["test", "ci_success"]);
+        assert_eq!(
+            sys.allowed_failures(),
+            vec!["clippy_build", "clippy_build-macos"]
+        );
     }
 }
@@ -1,4 +1,5 @@
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::env::current_dir;
 use std::fs::read_to_string;
@@ -7,13 +8,13 @@ use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use cargo_metadata;
 use custom_error::custom_error;
 use serde::de::{Deserialize, Deserializer};
 use serde::ser::Serialize;
 use serde_derive::{Deserialize, Serialize};
-use serde_json;
-use toml;
+
+use crate::nightly;
+use crate::toolchain::{self, Toolchain, ToolchainFileMode};
 
 trait OptionDeref<T: Deref> {
     fn as_deref_option(&self) -> Option<&T::Target>;
@@ -47,6 +48,82 @@ impl ExecutorEntry {
     }
 }
 
+/// The operating systems that jobs can run on.
+const KNOWN_OSES: &[&str] = &["linux", "macos", "windows"];
+
+/// An operating system in the OS matrix.
+#[derive(Debug, Deserialize, PartialEq)]
+pub(crate) struct OsEntry {
+    pub(crate) name: String,
+
+    /// The distribution to run on (travis only). Defaults to the
+    /// top-level `dist` setting on linux.
+    #[serde(default)]
+    pub(crate) dist: Option<String>,
+
+    /// The VM image to run on: the `osx_image` on travis, and the
+    /// xcode version or machine image on CircleCI.
+    #[serde(default)]
+    pub(crate) image: Option<String>,
+}
+
+impl OsEntry {
+    fn named(name: &str) -> OsEntry {
+        OsEntry {
+            name: name.to_string(),
+            dist: None,
+            image: None,
+        }
+    }
+
+    /// Returns the name that travis uses for this OS.
+    pub(crate) fn travis_name(&self) -> &str {
+        match self.name.as_str() {
+            "macos" => "osx",
+            name => name,
+        }
+    }
+
+    /// Returns true if CircleCI jobs on this OS run in the per-version
+    /// docker executors. All other OSes use an executor named after
+    /// the OS, and install rust in the job.
+    pub(crate) fn uses_docker(&self) -> bool {
+        self.name == "linux" && self.image.is_none()
+    }
+}
+
+/// Deserializes the OS matrix from either a single OS name or a list
+/// of OS names / tables.
+fn deserialize_os<'de, D>(deserializer: D) -> Result<Vec<OsEntry>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OsSpec {
+        Name(String),
+        Entry(OsEntry),
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OsList {
+        Single(String),
+        List(Vec<OsSpec>),
+    }
+
+    Ok(match OsList::deserialize(deserializer)? {
+        OsList::Single(name) => vec![OsEntry::named(&name)],
+        OsList::List(specs) => specs
+            .into_iter()
+            .map(|spec| match spec {
+                OsSpec::Name(name) => OsEntry::named(&name),
+                OsSpec::Entry(entry) => entry,
+            })
+            .collect(),
+    })
+}
+
 #[derive(Debug)]
 pub(crate) struct MatrixEntry {
     pub(crate) run: bool,
@@ -56,9 +133,59 @@ pub(crate) struct MatrixEntry {
     // TODO: this needs to be shell-escaped!
     pub(crate) install_commandline: Option<String>,
 
-    pub(crate) commandline: String,
+    /// The command line to run; None for built-in entries whose
+    /// command line derives from other settings.
+    pub(crate) commandline: Option<String>,
 
     pub(crate) timeout: Option<Duration>,
+
+    /// The operating systems to run on; if empty, the entry only runs
+    /// on the first OS in the OS matrix.
+    pub(crate) os: Vec<String>,
+
+    /// The names of the jobs that have to succeed before this entry
+    /// runs.
+    pub(crate) requires: Vec<String>,
+
+    /// Whether the build succeeds even if this entry fails.
+    pub(crate) allow_failure: bool,
+
+    /// The number of CircleCI containers that the entry's tests get
+    /// split across.
+    pub(crate) parallelism: Option<u32>,
+
+    /// Cargo subcommands and other tools to install, as
+    /// `<crate>[@<version>]`.
+    pub(crate) tools: Vec<String>,
+
+    /// The rustup components to add.
+    pub(crate) components: Vec<String>,
+
+    /// The rustup targets to add.
+    pub(crate) targets: Vec<String>,
+}
+
+impl MatrixEntry {
+    /// Returns the command line that adds the entry's rustup
+    /// components and targets, followed by `install`.
+    pub(crate) fn with_rustup(&self, install: Option<String>) -> Option<String> {
+        let mut cmdlines = vec![];
+        if !self.components.is_empty() {
+            cmdlines.push(format!(
+                "rustup component add {}",
+                self.components.join(" ")
+            ));
+        }
+        if !self.targets.is_empty() {
+            cmdlines.push(format!("rustup target add {}", self.targets.join(" ")));
+        }
+        cmdlines.extend(install);
+        if cmdlines.is_empty() {
+            None
+        } else {
+            Some(cmdlines.join(" && "))
+        }
+    }
 }
 
 pub(crate) trait MatrixEntryExt {
@@ -76,18 +203,29 @@ pub(crate) trait MatrixEntryExt {
         &(self.the_entry().version)
     }
 
-    fn install_commandline(&self) -> Option<&str> {
-        self.the_entry().install_commandline.as_deref_option()
+    /// Returns the command line that installs what the entry needs:
+    /// its rustup components and targets, and whatever its
+    /// `install_commandline` installs.
+    fn install_commandline(&self) -> Option<String> {
+        let entry = self.the_entry();
+        entry.with_rustup(entry.install_commandline.clone())
     }
 
     fn commandline(&self) -> &str {
-        &(self.the_entry().commandline)
+        self.the_entry().commandline.as_deref().unwrap_or_default()
+    }
+
+    /// Returns the timeout if the entry doesn't set one.
+    fn default_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        self.the_entry().timeout.or(self.default_timeout())
     }
 
     fn timeout(&self) -> Option<String> {
-        self.the_entry()
-            .timeout
-            .map(|to| format!("{}s", to.as_secs()))
+        self.duration().map(|to| format!("{}s", to.as_secs()))
     }
 }
 
@@ -100,8 +238,9 @@ define_matrix_entry!(
     (
         true,
         "stable",
-        "rustup component add clippy".to_owned(),
-        "cargo clippy -- -D warnings".to_owned()
+        None,
+        "cargo clippy -- -D warnings".to_owned(),
+        vec!["clippy"]
     )
 );
 define_matrix_entry!(
@@ -109,232 +248,2375 @@ define_matrix_entry!(
     (
         true,
         "stable",
-        "rustup component add rustfmt".to_owned(),
-        "cargo fmt -v -- --check".to_owned()
+        None,
+        "cargo fmt -v -- --check".to_owned(),
+        vec!["rustfmt"]
     )
 );
 
-define_matrix_entry!(CustomEntry, (false, "stable", None, None));
+// The command lines of the following entries default to ones derived
+// from other settings; see `TemplateCIConfig::builtin_jobs`.
+define_matrix_entry!(BuiltinEntry, (false, "stable", None, None));
+define_matrix_entry!(
+    CoverageEntry,
+    (false, "stable", None, None, vec!["llvm-tools-preview"])
+);
+define_matrix_entry!(
+    MiriEntry,
+    (
+        false,
+        "nightly",
+        "cargo miri setup".to_owned(),
+        None,
+        vec!["miri", "rust-src"]
+    )
+);
+define_matrix_entry!(
+    SanitizerEntry,
+    (false, "nightly", None, None, vec!["rust-src"])
+);
+define_matrix_entry!(CanaryEntry, (false, "nightly", None, None, vec!["clippy"]));
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct TemplateCIConfig {
-    #[serde(default)]
-    pub(crate) bench: BenchEntry,
+impl CanaryEntry {
+    /// Returns the entry the way the canary job uses it: only in
+    /// scheduled builds, and allowed to fail.
+    fn scheduled(self) -> Self {
+        CanaryEntry(MatrixEntry {
+            run: false,
+            run_cron: true,
+            allow_failure: true,
+            ..self.0
+        })
+    }
 
-    #[serde(default)]
-    pub(crate) clippy: ClippyEntry,
+    fn deserialize_scheduled<'de, D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(CanaryEntry::deserialize(deserializer)?.scheduled())
+    }
+}
 
-    #[serde(default)]
-    pub(crate) rustfmt: RustfmtEntry,
+define_matrix_entry!(CustomEntry, (true, "stable", None, None));
 
-    #[serde(default)]
-    pub(crate) additional_matrix_entries: HashMap<String, CustomEntry>,
+/// The tool that measures code coverage.
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum CoverageTool {
+    #[default]
+    LlvmCov,
+    Tarpaulin,
+}
 
-    #[serde(default = "TemplateCIConfig::default_cache")]
-    pub(crate) cache: String,
+/// The format of coverage reports.
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CoverageFormat {
+    #[default]
+    Lcov,
+    Cobertura,
+}
 
-    #[serde(default = "TemplateCIConfig::default_os")]
-    pub(crate) os: String,
+/// The directory that coverage reports get written to.
+const COVERAGE_DIR: &str = "target/coverage";
 
-    #[serde(default = "TemplateCIConfig::default_dist")]
-    pub(crate) dist: String,
+/// A sanitizer that the sanitizer job builds the tests with.
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Sanitizer {
+    Address,
+    Leak,
+    Memory,
+    Thread,
+}
 
-    #[serde(default = "TemplateCIConfig::default_versions")]
-    pub(crate) versions: Vec<String>,
+impl Sanitizer {
+    fn as_str(self) -> &'static str {
+        match self {
+            Sanitizer::Address => "address",
+            Sanitizer::Leak => "leak",
+            Sanitizer::Memory => "memory",
+            Sanitizer::Thread => "thread",
+        }
+    }
+}
 
-    #[serde(default = "TemplateCIConfig::default_test_commandline")]
-    pub(crate) test_commandline: String,
+/// Prints the host's target triple, which the sanitizer job has to
+/// pass to cargo explicitly for `-Zbuild-std`.
+const HOST_TRIPLE: &str = "$(rustc -vV | grep host | cut -d\" \" -f2)";
 
-    #[serde(default = "TemplateCIConfig::default_scheduled_test_branches")]
-    pub(crate) scheduled_test_branches: Vec<String>,
+/// The names of all built-in jobs, including the ones that don't run.
+const BUILTIN_JOB_NAMES: &[&str] = &[
+    "coverage",
+    "audit",
+    "deny",
+    "miri",
+    "sanitizer",
+    "docs",
+    "semver_checks",
+    "canary",
+];
 
-    #[serde(default = "TemplateCIConfig::default_test_schedule")]
-    pub(crate) test_schedule: String,
+/// A built-in matrix entry, with the command lines it runs.
+#[derive(Debug)]
+pub(crate) struct BuiltinJob<'a> {
+    pub(crate) name: &'static str,
+    pub(crate) entry: &'a MatrixEntry,
+    pub(crate) install_commandline: Option<String>,
+    pub(crate) commandline: String,
 
-    #[serde(default = "TemplateCIConfig::default_executors")]
-    pub(crate) executors: Vec<ExecutorEntry>,
+    /// Tools that the job installs in addition to the entry's.
+    pub(crate) tools: Vec<&'static str>,
 
-    #[serde(default)]
-    pub(crate) additional_executors: Vec<ExecutorEntry>,
+    /// A directory whose contents get stored as build artifacts.
+    pub(crate) artifacts: Option<&'static str>,
+
+    /// A command that runs after the job succeeded.
+    pub(crate) after_success: Option<String>,
+
+    /// The timeout if the entry doesn't set one.
+    pub(crate) default_timeout: Option<Duration>,
+
+    /// Whether the job runs on builds of release tags.
+    pub(crate) release_tags: bool,
 }
 
-impl Default for TemplateCIConfig {
+impl BuiltinJob<'_> {
+    /// Returns the suffix of the environment variable that enables
+    /// this job on travis.
+    pub(crate) fn env_name(&self) -> String {
+        self.name.to_ascii_uppercase()
+    }
+}
+
+impl MatrixEntryExt for BuiltinJob<'_> {
+    fn the_entry(&'_ self) -> &'_ MatrixEntry {
+        self.entry
+    }
+
+    fn default_timeout(&self) -> Option<Duration> {
+        self.default_timeout
+    }
+}
+
+/// Which combinations of individual features get tested.
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FeatureCombinations {
+    /// Don't test individual features.
+    None,
+
+    /// Test each feature on its own.
+    #[default]
+    Each,
+
+    /// Test every combination of up to `max_combination_size` features.
+    Powerset,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct FeaturesConfig {
+    pub(crate) run: bool,
+    pub(crate) version: String,
+    pub(crate) commandline: String,
+    pub(crate) no_default_features: bool,
+    pub(crate) all_features: bool,
+    pub(crate) combinations: FeatureCombinations,
+    pub(crate) max_combination_size: usize,
+    pub(crate) include: Vec<String>,
+    pub(crate) exclude: Vec<String>,
+
+    /// The features that the package defines, read from cargo metadata.
+    #[serde(skip)]
+    pub(crate) available: Vec<String>,
+}
+
+impl Default for FeaturesConfig {
     fn default() -> Self {
-        TemplateCIConfig {
-            clippy: Default::default(),
-            bench: Default::default(),
-            rustfmt: Default::default(),
-            additional_matrix_entries: Default::default(),
-            dist: "xenial".to_string(),
-            cache: "cargo".to_string(),
-            os: "linux".to_string(),
-            versions: vec!["stable", "nightly"]
-                .into_iter()
-                .map(String::from)
-                .collect(),
-            test_commandline: "cargo test --verbose --all".to_owned(),
-            scheduled_test_branches: vec!["master"].into_iter().map(String::from).collect(),
-            test_schedule: "0 0 * * 0".to_string(), // every sunday at 0:00 UTC
-            executors: vec![
-                ExecutorEntry {
-                    name: "stable".to_string(),
-                    image_name: "liuchong/rustup:stable".to_string(),
-                },
-                ExecutorEntry {
-                    name: "beta".to_string(),
-                    image_name: "liuchong/rustup:beta".to_string(),
-                },
-                ExecutorEntry {
-                    name: "nightly".to_string(),
-                    image_name: "liuchong/rustup:nightly".to_string(),
-                },
-            ],
-            additional_executors: Default::default(),
+        FeaturesConfig {
+            run: false,
+            version: "stable".to_string(),
+            commandline: "cargo test --verbose".to_string(),
+            no_default_features: true,
+            all_features: true,
+            combinations: Default::default(),
+            max_combination_size: 2,
+            include: vec![],
+            exclude: vec![],
+            available: vec![],
         }
     }
 }
 
-impl<'a> TemplateCIConfig {
-    fn from_manifest(path: Option<&Path>) -> Result<(TemplateCIConfig, PathBuf), Error> {
-        #[derive(Debug, Deserialize)]
-        struct Metadata {
-            #[serde(default)]
-            template_ci: Option<TemplateCIConfig>,
+/// A single job in the feature matrix.
+#[derive(PartialEq, Debug)]
+pub(crate) struct FeatureJob {
+    pub(crate) name: String,
+    pub(crate) flags: String,
+}
+
+impl FeaturesConfig {
+    /// Returns the features that individual feature jobs get
+    /// generated for.
+    fn selected(&self) -> Vec<&str> {
+        let candidates = if self.include.is_empty() {
+            &self.available
+        } else {
+            &self.include
+        };
+        let mut selected: Vec<&str> = candidates
+            .iter()
+            .filter(|f| f.as_str() != "default" && !self.exclude.contains(f))
+            .map(String::as_str)
+            .collect();
+        selected.sort_unstable();
+        selected.dedup();
+        selected
+    }
+
+    /// Returns the jobs of the feature matrix, or nothing if it is
+    /// disabled.
+    pub(crate) fn jobs(&self) -> Vec<FeatureJob> {
+        let mut jobs = vec![];
+        if !self.run {
+            return jobs;
         }
-        let metadata = cargo_metadata::metadata(path)?;
-        let root_dir = match path {
-            None => {
-                let cargo_path = &metadata.packages[0].manifest_path;
-                let p = Path::new(cargo_path);
-                p.parent()
-                    .unwrap_or_else(|| panic!("The manifest at {:?} should live in a directory", p))
-                    .to_path_buf()
-            }
-            Some(path) => path
-                .parent()
-                .unwrap_or_else(|| panic!("The given path {:?} should have a parent dir", path))
-                .to_path_buf(),
+        if self.no_default_features {
+            jobs.push(FeatureJob {
+                name: "features-no-default".to_string(),
+                flags: "--no-default-features".to_string(),
+            });
+        }
+        if self.all_features {
+            jobs.push(FeatureJob {
+                name: "features-all".to_string(),
+                flags: "--all-features".to_string(),
+            });
+        }
+        let selected = self.selected();
+        let max_size = match self.combinations {
+            FeatureCombinations::None => 0,
+            FeatureCombinations::Each => 1,
+            FeatureCombinations::Powerset => self.max_combination_size,
         };
-        match &metadata.packages[0].metadata {
-            serde_json::Value::Null => Ok((Default::default(), root_dir)),
-            md => {
-                let metadata_str = md.to_string();
-                let config: Metadata = serde_json::from_str(&metadata_str)?;
-                Ok((config.template_ci.unwrap_or_default(), root_dir))
+        for size in 1..=max_size.min(selected.len()) {
+            for combination in combinations(&selected, size) {
+                jobs.push(FeatureJob {
+                    name: format!("features-{}", combination.join("+")),
+                    flags: format!("--no-default-features --features {}", combination.join(",")),
+                });
             }
         }
+        jobs
     }
 
-    fn from_config_file(
-        file_name: impl AsRef<Path>,
-        path: Option<&Path>,
-    ) -> Result<(TemplateCIConfig, PathBuf), Error> {
-        let file_name = file_name.as_ref();
-
-        let default = current_dir()?.join(file_name);
-        let path = path.unwrap_or(&default);
-        let config_src = read_to_string(path)?;
-        let config: TemplateCIConfig = toml::from_str(&config_src)?;
-        Ok((
-            config,
-            path.parent()
-                .expect("Impossible: config file has no parent")
-                .to_path_buf(),
-        ))
+    /// Checks that all explicitly listed features exist in the package.
+    fn validate(&self) -> Result<(), Error> {
+        if !self.run {
+            return Ok(());
+        }
+        match self
+            .include
+            .iter()
+            .chain(self.exclude.iter())
+            .find(|f| !self.available.contains(f))
+        {
+            Some(feature) => Err(Error::UnknownFeature {
+                feature: feature.to_string(),
+            }),
+            None => Ok(()),
+        }
     }
+}
 
-    pub(crate) fn merged_configs(
-        path: Option<&Path>,
-    ) -> Result<(TemplateCIConfig, PathBuf), Error> {
-        TemplateCIConfig::from_config_file("template-ci.toml", path)
-            .or_else(|_| TemplateCIConfig::from_config_file(".template-ci.toml", path))
-            .or_else(|_| TemplateCIConfig::from_manifest(path))
+/// The cargo subcommand that a target job runs.
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TargetCommand {
+    #[default]
+    Check,
+    Build,
+    Test,
+}
+
+impl TargetCommand {
+    fn as_str(self) -> &'static str {
+        match self {
+            TargetCommand::Check => "check",
+            TargetCommand::Build => "build",
+            TargetCommand::Test => "test",
+        }
     }
+}
 
-    fn default_cache() -> String {
-        Self::default().cache
+/// A cross-compilation target that gets its own job.
+#[derive(Debug, Deserialize)]
+pub(crate) struct TargetEntry {
+    pub(crate) triple: String,
+
+    #[serde(default)]
+    pub(crate) command: TargetCommand,
+
+    #[serde(default = "TargetEntry::default_version")]
+    pub(crate) version: String,
+
+    /// Extra arguments to the cargo command, e.g. `--no-default-features`.
+    #[serde(default)]
+    pub(crate) flags: Option<String>,
+
+    /// The runner for test binaries, e.g. `qemu-aarch64`.
+    #[serde(default)]
+    pub(crate) runner: Option<String>,
+
+    /// Installs anything besides the target itself, e.g. a runner.
+    #[serde(default)]
+    pub(crate) install_commandline: Option<String>,
+}
+
+impl TargetEntry {
+    fn default_version() -> String {
+        "stable".to_string()
     }
 
-    fn default_os() -> String {
-        Self::default().os
+    /// Returns the job name for this target.
+    pub(crate) fn name(&self) -> String {
+        format!("target-{}", self.triple)
     }
 
-    fn default_dist() -> String {
-        Self::default().dist
+    /// Returns the suffix for environment variable names referring
+    /// to this target.
+    pub(crate) fn env_name(&self) -> String {
+        self.triple.to_ascii_uppercase().replace(['-', '.'], "_")
     }
 
-    fn default_versions() -> Vec<String> {
-        Self::default().versions
+    /// Returns the command line that installs the target.
+    pub(crate) fn target_install_commandline(&self) -> String {
+        format!("rustup target add {}", self.triple)
     }
 
-    fn default_test_commandline() -> String {
-        Self::default().test_commandline
+    /// Returns the command line that checks, builds or tests the target.
+    pub(crate) fn commandline(&self) -> String {
+        let mut cmdline = String::new();
+        if let Some(runner) = self.runner.as_deref_option() {
+            cmdline.push_str(&format!(
+                "CARGO_TARGET_{}_RUNNER=\"{}\" ",
+                self.env_name(),
+                runner
+            ));
+        }
+        cmdline.push_str(&format!(
+            "cargo {} --verbose --target {}",
+            self.command.as_str(),
+            self.triple
+        ));
+        if let Some(flags) = self.flags.as_deref_option() {
+            cmdline.push(' ');
+            cmdline.push_str(flags);
+        }
+        cmdline
     }
+}
 
-    fn default_test_schedule() -> String {
-        Self::default().test_schedule
+/// The template-ci settings of a workspace member other than the root
+/// package.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+struct MemberConfig {
+    member_test: bool,
+    member_test_commandline: Option<String>,
+    additional_matrix_entries: HashMap<String, CustomEntry>,
+}
+
+/// A test job for a single workspace member.
+#[derive(Debug, PartialEq)]
+pub(crate) struct MemberTest {
+    pub(crate) name: String,
+    pub(crate) commandline: String,
+
+    /// The directory of this member, relative to the workspace root.
+    pub(crate) dir: String,
+
+    /// Whether the member has a library target, with unit and doc
+    /// tests.
+    pub(crate) has_lib_targets: bool,
+
+    /// The directories (relative to the workspace root) of this
+    /// member and all workspace members it depends on.
+    pub(crate) paths: Vec<String>,
+}
+
+impl MemberTest {
+    /// Returns the job name for this member's tests.
+    pub(crate) fn job_name(&self) -> String {
+        format!("test-{}", self.name)
     }
 
-    fn default_scheduled_test_branches() -> Vec<String> {
-        Self::default().scheduled_test_branches
+    /// Returns the suffix for environment variable names referring
+    /// to this member.
+    pub(crate) fn env_name(&self) -> String {
+        self.name.to_ascii_uppercase().replace('-', "_")
     }
 
-    fn default_executors() -> Vec<ExecutorEntry> {
-        Self::default().executors
+    /// Returns the name of the CircleCI pipeline parameter that
+    /// tells whether a change affects this member.
+    pub(crate) fn affected_parameter(&self) -> String {
+        format!("run-{}", self.job_name())
     }
 }
 
-impl TemplateCIConfig {
-    pub(crate) fn all_executors_for_circleci(&self) -> impl Serialize {
-        #[derive(Debug, Serialize)]
-        struct DockerCfg {
-            image: String,
-        }
-        #[derive(Debug, Serialize)]
-        enum Entry {
-            #[serde(rename = "docker")]
-            Docker(Vec<DockerCfg>),
-        }
-
-        let all_executors = self
-            .executors
-            .iter()
-            .chain(self.additional_executors.iter());
+/// How tools get installed.
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ToolInstaller {
+    /// Build them with `cargo install --locked`.
+    #[default]
+    Cargo,
 
-        let entry: BTreeMap<String, Entry> = all_executors
-            .map(|e| {
-                (
-                    e.name.clone(),
-                    Entry::Docker(vec![DockerCfg {
-                        image: e.image_name.to_string(),
-                    }]),
-                )
-            })
-            .collect();
-        entry
-    }
+    /// Download binaries with cargo-binstall.
+    Binstall,
 }
 
-custom_error! {pub Error
-               CargoError{source: cargo_metadata::Error} = "Could not get cargo metadata",
-               Deserialization{source: serde_json::Error} = "Could not parse cargo metadata",
-               TOMLDeserialization{source: toml::de::Error} = "Could not parse TOML configuration file",
-               IO{source: io::Error} = "IO",
+/// The directory that tools get installed into, which gets cached.
+const TOOLS_DIR: &str = "$HOME/.cargo-tools";
+
+/// The toolchain that builds the tools, whatever toolchain the job
+/// tests with: Current tools don't build on an old MSRV or pinned
+/// toolchain.
+const TOOLS_TOOLCHAIN: &str = "stable";
+
+/// The tool that runs the tests.
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TestRunner {
+    #[default]
+    Cargo,
+    Nextest,
 }
 
-#[cfg(test)]
-mod tests {
-    use custom_error::*;
-    use std::fs::File;
-    use std::io;
-    use std::io::Write;
-    use std::path::PathBuf;
-    use tempfile;
+/// The `cargo test` flags that select which targets to test.
+const TARGET_SELECTION_FLAGS: &[&str] = &[
+    "--lib",
+    "--bin",
+    "--bins",
+    "--example",
+    "--examples",
+    "--test",
+    "--tests",
+    "--bench",
+    "--benches",
+    "--all-targets",
+    "--doc",
+];
 
-    use super::TemplateCIConfig;
+/// The JUnit report that cargo-nextest writes with the `ci` profile.
+const NEXTEST_JUNIT_PATH: &str = "target/nextest/ci/junit.xml";
+
+/// Settings for publishing the package when a version tag gets
+/// pushed.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct ReleaseConfig {
+    pub(crate) run: bool,
+
+    /// The environment variable that holds the registry token.
+    pub(crate) token_env: String,
+
+    /// The name of the package that gets published, from the cargo
+    /// metadata.
+    #[serde(skip)]
+    pub(crate) package: Option<String>,
+}
+
+impl Default for ReleaseConfig {
+    fn default() -> Self {
+        ReleaseConfig {
+            run: false,
+            token_env: "CARGO_REGISTRY_TOKEN".to_string(),
+            package: None,
+        }
+    }
+}
+
+impl ReleaseConfig {
+    /// Returns the command line of the release job: On builds of a
+    /// tag (in the `tag_var` environment variable), it checks that the
+    /// tag matches the package version and publishes the package.
+    /// Other builds only do a dry run. `cargo pkgid` needs a lock
+    /// file, which libraries often don't check in.
+    pub(crate) fn commandline(&self, tag_var: &str) -> String {
+        let package = self.package.as_deref().unwrap_or_default();
+        let publish = if self.token_env == "CARGO_REGISTRY_TOKEN" {
+            "cargo publish".to_string()
+        } else {
+            format!(
+                r#"CARGO_REGISTRY_TOKEN="${}" cargo publish"#,
+                self.token_env
+            )
+        };
+        format!(
+            concat!(
+                r#"if [[ -n "${tag}" ]]; then "#,
+                "[[ -f Cargo.lock ]] || cargo generate-lockfile; ",
+                r#"version=$(cargo pkgid -p {package} | sed -e "s/.*[#@:]//"); "#,
+                r#"if [[ "${tag}" != "v$version" ]]; then "#,
+                r#"echo "Tag ${tag} does not match version $version"; exit 1; "#,
+                "fi; ",
+                "{publish}; ",
+                "else cargo publish --dry-run; fi"
+            ),
+            tag = tag_var,
+            package = package,
+            publish = publish,
+        )
+    }
+}
+
+/// Settings for skipping the test jobs of workspace members that
+/// are not affected by a change.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct ChangeDetection {
+    pub(crate) run: bool,
+
+    /// The revision that CircleCI compares changes against.
+    pub(crate) base_revision: String,
+
+    /// Paths that affect every member when they change. Paths ending
+    /// in `/` stand for everything in that directory.
+    pub(crate) shared_paths: Vec<String>,
+}
+
+impl Default for ChangeDetection {
+    fn default() -> Self {
+        ChangeDetection {
+            run: false,
+            base_revision: "master".to_string(),
+            shared_paths: vec![
+                "Cargo.toml",
+                "Cargo.lock",
+                "rust-toolchain",
+                "rust-toolchain.toml",
+                ".travis.yml",
+                ".circleci/",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        }
+    }
+}
+
+impl ChangeDetection {
+    /// Returns the regular expressions matching the changed paths
+    /// that affect a member.
+    pub(crate) fn path_patterns(&self, member: &MemberTest) -> Vec<String> {
+        member
+            .paths
+            .iter()
+            .map(|dir| {
+                if dir.is_empty() {
+                    ".*".to_string()
+                } else {
+                    format!("{}/.*", escape_regex(dir))
+                }
+            })
+            .chain(self.shared_paths.iter().map(|path| {
+                if path.ends_with('/') {
+                    format!("{}.*", escape_regex(path))
+                } else {
+                    escape_regex(path)
+                }
+            }))
+            .collect()
+    }
+
+    /// Returns a single extended regular expression that matches
+    /// every changed path affecting a member.
+    pub(crate) fn affected_regex(&self, member: &MemberTest) -> String {
+        format!("^({})$", self.path_patterns(member).join("|"))
+    }
+}
+
+/// Where the configuration gets read from.
+pub(crate) enum ConfigSource {
+    /// A `template-ci.toml` or `.template-ci.toml` file.
+    File(PathBuf),
+    /// The manifest, whose `cargo metadata` also fills in the rest of
+    /// the configuration.
+    Manifest(cargo_metadata::Metadata),
+}
+
+impl ConfigSource {
+    /// Returns the file that holds the configuration, and the path
+    /// of the template-ci table in it.
+    pub(crate) fn table(&self) -> (PathBuf, &'static [&'static str]) {
+        match self {
+            ConfigSource::File(file) => (file.clone(), &[]),
+            ConfigSource::Manifest(metadata) => match metadata.root_package() {
+                Some(package) => (
+                    package.manifest_path.clone().into_std_path_buf(),
+                    &["package", "metadata", "template_ci"],
+                ),
+                None => (
+                    metadata
+                        .workspace_root
+                        .join("Cargo.toml")
+                        .into_std_path_buf(),
+                    &["workspace", "metadata", "template_ci"],
+                ),
+            },
+        }
+    }
+}
+
+/// Runs `cargo metadata` on the manifest at `path`, or on the one
+/// that cargo finds from the current directory.
+fn read_metadata(path: Option<&Path>) -> Result<cargo_metadata::Metadata, Error> {
+    let mut cmd = cargo_metadata::MetadataCommand::new();
+    if let Some(path) = path {
+        cmd.manifest_path(path);
+    }
+    Ok(cmd.no_deps().exec()?)
+}
+
+/// Returns true if a test command line can get split across CircleCI
+/// containers: `cargo nextest run` command lines, and `cargo test`
+/// command lines that don't pick their own test targets.
+fn splits_commandline(cmdline: &str) -> bool {
+    if cmdline.starts_with("cargo nextest run") {
+        return true;
+    }
+    match cmdline.strip_prefix("cargo test") {
+        Some(args) => !args
+            .split_whitespace()
+            .take_while(|arg| *arg != "--")
+            .any(|arg| TARGET_SELECTION_FLAGS.contains(&arg)),
+        None => false,
+    }
+}
+
+/// Escapes the characters in a path that have a meaning in regular
+/// expressions.
+fn escape_regex(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        if ".+*?()[]{}|^$\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Appends a test name filter to a `cargo test` command line.
+fn with_test_filter(cmdline: &str, filter: &Option<String>) -> String {
+    match filter {
+        Some(filter) => format!("{} -- {}", cmdline, filter),
+        None => cmdline.to_string(),
+    }
+}
+
+/// Returns the tools that a job installs by default: just `krate`,
+/// unless the configured `tools` already pick a version of it.
+fn default_tools(tools: &[String], krate: &'static str) -> Vec<&'static str> {
+    let picked = tools
+        .iter()
+        .any(|tool| tool.split('@').next() == Some(krate));
+    if picked {
+        vec![]
+    } else {
+        vec![krate]
+    }
+}
+
+/// Returns true if a package has a library or proc-macro target.
+fn has_lib_target(package: &cargo_metadata::Package) -> bool {
+    package.targets.iter().any(|target| {
+        target
+            .kind
+            .iter()
+            .any(|kind| kind.ends_with("lib") || kind == "proc-macro")
+    })
+}
+
+/// Returns the names of a workspace member and of all workspace
+/// members it (transitively) depends on, sorted by name.
+fn workspace_dependencies<'a>(
+    name: &'a str,
+    dependencies: &HashMap<&'a str, Vec<&'a str>>,
+) -> Vec<&'a str> {
+    let mut seen = BTreeSet::new();
+    let mut todo = vec![name];
+    while let Some(name) = todo.pop() {
+        if seen.insert(name) {
+            todo.extend(dependencies.get(name).into_iter().flatten());
+        }
+    }
+    seen.into_iter().collect()
+}
+
+/// A job that other jobs can require.
+struct Requirement<'a> {
+    run: bool,
+    run_cron: bool,
+    requires: &'a [String],
+}
+
+/// Computes the depth of a job in the requirement graph, failing if
+/// the job is part of a cycle. `path` holds the jobs that (directly
+/// or indirectly) require this one.
+fn requirement_depth<'a>(
+    name: &'a str,
+    graph: &BTreeMap<&'a str, Requirement<'a>>,
+    depths: &mut BTreeMap<&'a str, usize>,
+    path: &mut Vec<&'a str>,
+) -> Result<usize, Error> {
+    if let Some(depth) = depths.get(name) {
+        return Ok(*depth);
+    }
+    if let Some(start) = path.iter().position(|job| *job == name) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(name);
+        return Err(Error::RequirementCycle {
+            cycle: cycle.join(" -> "),
+        });
+    }
+    path.push(name);
+    let mut depth = 0;
+    for requirement in graph[name].requires {
+        depth = depth.max(requirement_depth(requirement, graph, depths, path)? + 1);
+    }
+    path.pop();
+    depths.insert(name, depth);
+    Ok(depth)
+}
+
+/// Returns all combinations of `size` elements from `items`, in order.
+fn combinations<'a>(items: &[&'a str], size: usize) -> Vec<Vec<&'a str>> {
+    if size == 0 {
+        return vec![vec![]];
+    }
+    let mut result = vec![];
+    for (i, item) in items.iter().enumerate() {
+        for mut rest in combinations(&items[i + 1..], size - 1) {
+            rest.insert(0, *item);
+            result.push(rest);
+        }
+    }
+    result
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TemplateCIConfig {
+    #[serde(default)]
+    pub(crate) bench: BenchEntry,
+
+    #[serde(default)]
+    pub(crate) clippy: ClippyEntry,
+
+    #[serde(default)]
+    pub(crate) rustfmt: RustfmtEntry,
+
+    #[serde(default)]
+    pub(crate) coverage: CoverageEntry,
+
+    #[serde(default)]
+    pub(crate) coverage_tool: CoverageTool,
+
+    #[serde(default)]
+    pub(crate) coverage_format: CoverageFormat,
+
+    /// A command that uploads the coverage report.
+    #[serde(default)]
+    pub(crate) coverage_upload_commandline: Option<String>,
+
+    #[serde(default)]
+    pub(crate) audit: BuiltinEntry,
+
+    #[serde(default)]
+    pub(crate) deny: BuiltinEntry,
+
+    /// Run `audit` and `deny` on schedule only, since advisories get
+    /// published independent of commits.
+    #[serde(default)]
+    pub(crate) security_checks_scheduled_only: bool,
+
+    #[serde(default)]
+    pub(crate) miri: MiriEntry,
+
+    /// Only run the tests matching this filter under miri.
+    #[serde(default)]
+    pub(crate) miri_test_filter: Option<String>,
+
+    #[serde(default)]
+    pub(crate) sanitizer: SanitizerEntry,
+
+    #[serde(default = "TemplateCIConfig::default_sanitizers")]
+    pub(crate) sanitizers: Vec<Sanitizer>,
+
+    /// Only run the tests matching this filter with sanitizers.
+    #[serde(default)]
+    pub(crate) sanitizer_test_filter: Option<String>,
+
+    #[serde(default)]
+    pub(crate) docs: BuiltinEntry,
+
+    /// The `RUSTDOCFLAGS` that the docs get built with.
+    #[serde(default = "TemplateCIConfig::default_docs_rustdocflags")]
+    pub(crate) docs_rustdocflags: String,
+
+    /// Also run the doc tests in the docs job.
+    #[serde(default)]
+    pub(crate) docs_test: bool,
+
+    #[serde(default)]
+    pub(crate) semver_checks: BuiltinEntry,
+
+    /// The git revision to check the API against, instead of the
+    /// latest release on crates.io.
+    #[serde(default)]
+    pub(crate) semver_checks_baseline: Option<String>,
+
+    #[serde(default)]
+    pub(crate) additional_matrix_entries: HashMap<String, CustomEntry>,
+
+    #[serde(default)]
+    pub(crate) features: FeaturesConfig,
+
+    #[serde(default)]
+    pub(crate) targets: Vec<TargetEntry>,
+
+    /// Workspace members with their own test jobs, collected from
+    /// the members' package metadata.
+    #[serde(skip)]
+    pub(crate) member_tests: Vec<MemberTest>,
+
+    #[serde(default)]
+    pub(crate) change_detection: ChangeDetection,
+
+    #[serde(default)]
+    pub(crate) release: ReleaseConfig,
+
+    #[serde(default = "TemplateCIConfig::default_cache")]
+    pub(crate) cache: String,
+
+    /// Part of every CircleCI cache key; changing it discards all
+    /// existing caches.
+    #[serde(default = "TemplateCIConfig::default_cache_version")]
+    pub(crate) cache_version: String,
+
+    /// The sccache release that `cache = "sccache"` installs.
+    #[serde(default = "TemplateCIConfig::default_sccache_version")]
+    pub(crate) sccache_version: String,
+
+    #[serde(default = "TemplateCIConfig::default_os")]
+    #[serde(deserialize_with = "deserialize_os")]
+    pub(crate) os: Vec<OsEntry>,
+
+    #[serde(default = "TemplateCIConfig::default_dist")]
+    pub(crate) dist: String,
+
+    #[serde(default = "TemplateCIConfig::default_versions")]
+    pub(crate) versions: Vec<String>,
+
+    #[serde(default = "TemplateCIConfig::default_test_commandline")]
+    pub(crate) test_commandline: String,
+
+    #[serde(default)]
+    pub(crate) test_runner: TestRunner,
+
+    /// Tools that every job but the uncached rustfmt job installs, as
+    /// `<crate>[@<version>]`.
+    #[serde(default)]
+    pub(crate) tools: Vec<String>,
+
+    #[serde(default)]
+    pub(crate) tool_installer: ToolInstaller,
+
+    /// The number of CircleCI containers that the tests get split
+    /// across.
+    #[serde(default)]
+    pub(crate) test_parallelism: Option<u32>,
+
+    /// Whether any package in the workspace has a library target,
+    /// whose unit and doc tests split test jobs run on the first
+    /// container.
+    #[serde(skip, default = "TemplateCIConfig::default_has_lib_targets")]
+    pub(crate) has_lib_targets: bool,
+
+    /// The names of the jobs that have to succeed before the test
+    /// jobs run.
+    #[serde(default)]
+    pub(crate) test_requires: Vec<String>,
+
+    /// Whether to generate a `ci_success` job that only succeeds if
+    /// all jobs that aren't allowed to fail succeed.
+    #[serde(default)]
+    pub(crate) ci_success: bool,
+
+    #[serde(default = "TemplateCIConfig::default_scheduled_test_branches")]
+    pub(crate) scheduled_test_branches: Vec<String>,
+
+    #[serde(default = "TemplateCIConfig::default_test_schedule")]
+    pub(crate) test_schedule: String,
+
+    /// Whether to run the test and clippy commands on the latest
+    /// nightly in scheduled builds, as an early warning that doesn't
+    /// fail the build.
+    #[serde(default)]
+    pub(crate) canary: bool,
+
+    /// The matrix entry of the canary job. It can't be moved out of
+    /// the scheduled builds.
+    #[serde(
+        default = "TemplateCIConfig::default_canary_entry",
+        deserialize_with = "CanaryEntry::deserialize_scheduled"
+    )]
+    pub(crate) canary_entry: CanaryEntry,
+
+    #[serde(default = "TemplateCIConfig::default_executors")]
+    pub(crate) executors: Vec<ExecutorEntry>,
+
+    #[serde(default)]
+    pub(crate) additional_executors: Vec<ExecutorEntry>,
+
+    #[serde(default)]
+    pub(crate) msrv: Option<String>,
+
+    #[serde(default = "TemplateCIConfig::default_msrv_commandline")]
+    pub(crate) msrv_commandline: String,
+
+    #[serde(default)]
+    pub(crate) toolchain_file: ToolchainFileMode,
+
+    /// The toolchain pinned in the repo root, detected at load time.
+    #[serde(skip)]
+    pub(crate) toolchain: Option<Toolchain>,
+
+    /// The depth of each job in the requirement graph, computed when
+    /// the configuration gets validated.
+    #[serde(skip)]
+    pub(crate) stages: BTreeMap<String, usize>,
+}
+
+impl Default for TemplateCIConfig {
+    fn default() -> Self {
+        TemplateCIConfig {
+            clippy: Default::default(),
+            bench: Default::default(),
+            coverage: Default::default(),
+            coverage_tool: Default::default(),
+            coverage_format: Default::default(),
+            coverage_upload_commandline: None,
+            audit: Default::default(),
+            deny: Default::default(),
+            security_checks_scheduled_only: false,
+            miri: Default::default(),
+            miri_test_filter: None,
+            sanitizer: Default::default(),
+            sanitizers: vec![Sanitizer::Address],
+            sanitizer_test_filter: None,
+            docs: Default::default(),
+            docs_rustdocflags: "-D warnings".to_string(),
+            docs_test: false,
+            semver_checks: Default::default(),
+            semver_checks_baseline: None,
+            rustfmt: Default::default(),
+            additional_matrix_entries: Default::default(),
+            features: Default::default(),
+            targets: Default::default(),
+            member_tests: Default::default(),
+            change_detection: Default::default(),
+            release: Default::default(),
+            dist: "xenial".to_string(),
+            cache: "cargo".to_string(),
+            cache_version: "v1".to_string(),
+            sccache_version: "0.7.7".to_string(),
+            os: vec![OsEntry::named("linux")],
+            versions: vec!["stable", "nightly"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            test_commandline: "cargo test --verbose --all".to_owned(),
+            test_runner: Default::default(),
+            tools: vec![],
+            tool_installer: Default::default(),
+            test_parallelism: None,
+            has_lib_targets: true,
+            test_requires: vec![],
+            ci_success: false,
+            scheduled_test_branches: vec!["master"].into_iter().map(String::from).collect(),
+            test_schedule: "0 0 * * 0".to_string(), // every sunday at 0:00 UTC
+            canary: false,
+            canary_entry: CanaryEntry::default().scheduled(),
+            executors: vec![
+                ExecutorEntry {
+                    name: "stable".to_string(),
+                    image_name: "liuchong/rustup:stable".to_string(),
+                },
+                ExecutorEntry {
+                    name: "beta".to_string(),
+                    image_name: "liuchong/rustup:beta".to_string(),
+                },
+                ExecutorEntry {
+                    name: "nightly".to_string(),
+                    image_name: "liuchong/rustup:nightly".to_string(),
+                },
+            ],
+            additional_executors: Default::default(),
+            msrv: None,
+            msrv_commandline: "cargo check --verbose --all".to_owned(),
+            toolchain_file: Default::default(),
+            toolchain: None,
+            stages: BTreeMap::new(),
+        }
+    }
+}
+
+impl TemplateCIConfig {
+    fn from_manifest(
+        metadata: &cargo_metadata::Metadata,
+    ) -> Result<(TemplateCIConfig, PathBuf), Error> {
+        #[derive(Debug, Deserialize)]
+        struct Metadata {
+            #[serde(default)]
+            template_ci: Option<TemplateCIConfig>,
+        }
+        let root_dir = metadata.workspace_root.clone().into_std_path_buf();
+        // Virtual workspaces can't have package metadata, so their
+        // config lives in the workspace metadata instead:
+        let package = metadata.root_package();
+        let md = match package {
+            Some(package) => &package.metadata,
+            None => &metadata.workspace_metadata,
+        };
+        let mut config: TemplateCIConfig = match md {
+            serde_json::Value::Null => Default::default(),
+            md => {
+                let metadata_str = md.to_string();
+                let config: Metadata = serde_json::from_str(&metadata_str)?;
+                config.template_ci.unwrap_or_default()
+            }
+        };
+        if config.msrv.is_none() {
+            config.msrv = package
+                .and_then(|package| package.rust_version.as_ref())
+                .map(ToString::to_string);
+        }
+        Ok((config, root_dir))
+    }
+
+    fn from_config_file(path: &Path) -> Result<(TemplateCIConfig, PathBuf), Error> {
+        let config_src = read_to_string(path)?;
+        let config: TemplateCIConfig = toml::from_str(&config_src)?;
+        Ok((
+            config,
+            path.parent()
+                .expect("Impossible: config file has no parent")
+                .to_path_buf(),
+        ))
+    }
+
+    fn config_file_path(
+        file_name: impl AsRef<Path>,
+        path: Option<&Path>,
+    ) -> Result<PathBuf, Error> {
+        // Config files live next to the manifest:
+        Ok(match path {
+            Some(manifest) => manifest.with_file_name(file_name.as_ref()),
+            None => current_dir()?.join(file_name),
+        })
+    }
+
+    /// Returns where the configuration gets read from: a config file
+    /// next to the manifest, or else the manifest's metadata.
+    pub(crate) fn config_source(path: Option<&Path>) -> Result<ConfigSource, Error> {
+        for file_name in &["template-ci.toml", ".template-ci.toml"] {
+            let config_file = TemplateCIConfig::config_file_path(file_name, path)?;
+            if config_file.is_file() {
+                return Ok(ConfigSource::File(config_file));
+            }
+        }
+        Ok(ConfigSource::Manifest(read_metadata(path)?))
+    }
+
+    pub(crate) fn merged_configs(
+        path: Option<&Path>,
+    ) -> Result<(TemplateCIConfig, PathBuf), Error> {
+        // Read the configuration from the same place that
+        // `bump-nightly` edits it in:
+        let (mut config, root_dir, metadata) = match TemplateCIConfig::config_source(path)? {
+            ConfigSource::File(file) => {
+                let (config, root_dir) = TemplateCIConfig::from_config_file(&file)?;
+                let manifest = root_dir.join("Cargo.toml");
+                let metadata = if manifest.is_file() {
+                    Some(read_metadata(Some(&manifest))?)
+                } else {
+                    None
+                };
+                (config, root_dir, metadata)
+            }
+            ConfigSource::Manifest(metadata) => {
+                let (config, root_dir) = TemplateCIConfig::from_manifest(&metadata)?;
+                (config, root_dir, Some(metadata))
+            }
+        };
+        config.toolchain = toolchain::detect(&root_dir)?;
+        config.schedule_security_checks();
+        if let Some(metadata) = metadata {
+            if let Some(package) = metadata.root_package() {
+                config.features.available = package.features.keys().cloned().collect();
+                config.release.package = Some(package.name.clone());
+            }
+            config.has_lib_targets = metadata
+                .workspace_packages()
+                .into_iter()
+                .any(has_lib_target);
+            config.merge_workspace_members(&metadata)?;
+        }
+        config.validate()?;
+        Ok((config, root_dir))
+    }
+
+    /// Moves the security checks into the scheduled workflow if
+    /// `security_checks_scheduled_only` is set.
+    fn schedule_security_checks(&mut self) {
+        if !self.security_checks_scheduled_only {
+            return;
+        }
+        for entry in [&mut self.audit.0, &mut self.deny.0] {
+            entry.run_cron = entry.run || entry.run_cron;
+            entry.run = false;
+        }
+    }
+
+    /// Checks the configuration for settings that can not result in
+    /// a working CI config, and computes the build stages.
+    pub(crate) fn validate(&mut self) -> Result<(), Error> {
+        self.features.validate()?;
+        if self.os.is_empty() {
+            return Err(Error::NoOs);
+        }
+        if let Some(os) = self
+            .os
+            .iter()
+            .find(|os| !KNOWN_OSES.contains(&os.name.as_str()))
+        {
+            return Err(Error::UnknownOs {
+                os: os.name.to_string(),
+            });
+        }
+        for (name, entry) in self.matrix_entries() {
+            if let Some(os) = entry
+                .os
+                .iter()
+                .find(|os| !self.os.iter().any(|matrix_os| &matrix_os.name == *os))
+            {
+                return Err(Error::EntryOsNotInMatrix {
+                    entry: name.to_string(),
+                    os: os.to_string(),
+                });
+            }
+        }
+        if let Some((name, _)) = self
+            .additional_matrix_entries
+            .iter()
+            .find(|(_, entry)| entry.the_entry().commandline.is_none())
+        {
+            return Err(Error::MissingCommandline {
+                entry: name.to_string(),
+            });
+        }
+        if self.test_parallelism.is_some() {
+            if !splits_commandline(&self.root_test_commandline()) {
+                return Err(Error::UnsplittableCommandline {
+                    entry: "test".to_string(),
+                });
+            }
+            if let Some(member) = self.member_tests.iter().find(|member| {
+                !splits_commandline(&self.test_runner_commandline(&member.commandline))
+            }) {
+                return Err(Error::UnsplittableCommandline {
+                    entry: member.name.to_string(),
+                });
+            }
+        }
+        if let Some((name, _)) = self.additional_matrix_entries.iter().find(|(_, entry)| {
+            let entry = entry.the_entry();
+            entry.parallelism.is_some()
+                && !splits_commandline(entry.commandline.as_deref().unwrap_or_default())
+        }) {
+            return Err(Error::UnsplittableCommandline {
+                entry: name.to_string(),
+            });
+        }
+        if let Some(msrv) = self.msrv.as_deref_option() {
+            let msrv_number = numeric_version(msrv).ok_or_else(|| Error::BadMsrv {
+                msrv: msrv.to_string(),
+            })?;
+            if let Some(version) = self.versions.iter().find(|v| {
+                numeric_version(v)
+                    .map(|number| number < msrv_number)
+                    .unwrap_or(false)
+            }) {
+                return Err(Error::MsrvTooNew {
+                    msrv: msrv.to_string(),
+                    version: version.to_string(),
+                });
+            }
+        }
+        self.stages = self
+            .requirement_depths()?
+            .into_iter()
+            .map(|(name, depth)| (name.to_string(), depth))
+            .collect();
+        if self.release.run && self.release.package.is_none() {
+            return Err(Error::ReleaseWithoutPackage);
+        }
+        Ok(())
+    }
+
+    /// Returns the built-in and custom matrix entries by name.
+    pub(crate) fn matrix_entries(&self) -> Vec<(&str, &MatrixEntry)> {
+        vec![
+            ("rustfmt", self.rustfmt.the_entry()),
+            ("clippy", self.clippy.the_entry()),
+            ("bench", self.bench.the_entry()),
+        ]
+        .into_iter()
+        .chain(
+            self.builtin_jobs()
+                .into_iter()
+                .map(|job| (job.name, job.entry)),
+        )
+        .chain(
+            self.additional_matrix_entries
+                .iter()
+                .map(|(name, entry)| (name.as_str(), entry.the_entry())),
+        )
+        .collect()
+    }
+
+    /// Returns the built-in matrix entries other than rustfmt, clippy
+    /// and bench that run on pushes or on schedule. Their command
+    /// lines default to ones derived from other settings.
+    pub(crate) fn builtin_jobs(&self) -> Vec<BuiltinJob<'_>> {
+        let mut jobs = vec![];
+        let coverage = self.coverage.the_entry();
+        if coverage.run || coverage.run_cron {
+            let (tool, tool_cmdline) = match self.coverage_tool {
+                CoverageTool::LlvmCov => (
+                    "cargo-llvm-cov",
+                    match self.coverage_format {
+                        CoverageFormat::Lcov => {
+                            format!(
+                                "cargo llvm-cov --workspace --lcov --output-path {}/lcov.info",
+                                COVERAGE_DIR
+                            )
+                        }
+                        CoverageFormat::Cobertura => format!(
+                            "cargo llvm-cov --workspace --cobertura --output-path {}/cobertura.xml",
+                            COVERAGE_DIR
+                        ),
+                    },
+                ),
+                CoverageTool::Tarpaulin => (
+                    "cargo-tarpaulin",
+                    format!(
+                        "cargo tarpaulin --workspace --out {} --output-dir {}",
+                        match self.coverage_format {
+                            CoverageFormat::Lcov => "Lcov",
+                            CoverageFormat::Cobertura => "Xml",
+                        },
+                        COVERAGE_DIR
+                    ),
+                ),
+            };
+            jobs.push(BuiltinJob {
+                name: "coverage",
+                entry: coverage,
+                install_commandline: coverage.install_commandline.clone(),
+                commandline: coverage
+                    .commandline
+                    .clone()
+                    .unwrap_or_else(|| format!("mkdir -p {} && {}", COVERAGE_DIR, tool_cmdline)),
+                tools: default_tools(&coverage.tools, tool),
+                artifacts: Some(COVERAGE_DIR),
+                after_success: self.coverage_upload_commandline.clone(),
+                default_timeout: None,
+                release_tags: true,
+            });
+        }
+        let security_checks = [
+            ("audit", &self.audit, "cargo-audit", "cargo audit"),
+            ("deny", &self.deny, "cargo-deny", "cargo deny check"),
+        ];
+        for (name, entry, krate, cmdline) in security_checks {
+            let entry = entry.the_entry();
+            if entry.run || entry.run_cron {
+                jobs.push(BuiltinJob {
+                    name,
+                    entry,
+                    install_commandline: entry.install_commandline.clone(),
+                    commandline: entry
+                        .commandline
+                        .clone()
+                        .unwrap_or_else(|| cmdline.to_string()),
+                    tools: default_tools(&entry.tools, krate),
+                    artifacts: None,
+                    after_success: None,
+                    default_timeout: None,
+                    release_tags: true,
+                });
+            }
+        }
+        let miri = self.miri.the_entry();
+        if miri.run || miri.run_cron {
+            jobs.push(BuiltinJob {
+                name: "miri",
+                entry: miri,
+                install_commandline: miri.install_commandline.clone(),
+                commandline: miri
+                    .commandline
+                    .clone()
+                    .unwrap_or_else(|| with_test_filter("cargo miri test", &self.miri_test_filter)),
+                tools: vec![],
+                artifacts: None,
+                after_success: None,
+                default_timeout: Some(Duration::from_secs(60 * 60)),
+                release_tags: true,
+            });
+        }
+        let sanitizer = self.sanitizer.the_entry();
+        if sanitizer.run || sanitizer.run_cron {
+            let test = with_test_filter(
+                &format!("cargo test -Zbuild-std --target {}", HOST_TRIPLE),
+                &self.sanitizer_test_filter,
+            );
+            let default_cmdline = self
+                .sanitizers
+                .iter()
+                .map(|sanitizer| {
+                    format!(
+                        "RUSTFLAGS=\"-Zsanitizer={0}\" RUSTDOCFLAGS=\"-Zsanitizer={0}\" {1}",
+                        sanitizer.as_str(),
+                        test
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" && ");
+            jobs.push(BuiltinJob {
+                name: "sanitizer",
+                entry: sanitizer,
+                install_commandline: sanitizer.install_commandline.clone(),
+                commandline: sanitizer.commandline.clone().unwrap_or(default_cmdline),
+                tools: vec![],
+                artifacts: None,
+                after_success: None,
+                default_timeout: Some(Duration::from_secs(30 * 60)),
+                release_tags: true,
+            });
+        }
+        let docs = self.docs.the_entry();
+        if docs.run || docs.run_cron {
+            let mut default_cmdline = "cargo doc --no-deps --all-features".to_string();
+            if !self.docs_rustdocflags.is_empty() {
+                default_cmdline = format!(
+                    "RUSTDOCFLAGS=\"{}\" {}",
+                    self.docs_rustdocflags, default_cmdline
+                );
+            }
+            if self.docs_test {
+                default_cmdline += " && cargo test --doc --all-features";
+            }
+            jobs.push(BuiltinJob {
+                name: "docs",
+                entry: docs,
+                install_commandline: docs.install_commandline.clone(),
+                commandline: docs.commandline.clone().unwrap_or(default_cmdline),
+                tools: vec![],
+                artifacts: Some("target/doc"),
+                after_success: None,
+                default_timeout: None,
+                release_tags: true,
+            });
+        }
+        let semver_checks = self.semver_checks.the_entry();
+        if semver_checks.run || semver_checks.run_cron {
+            let mut default_cmdline = "cargo semver-checks".to_string();
+            if let Some(baseline) = &self.semver_checks_baseline {
+                default_cmdline += &format!(" --baseline-rev {}", baseline);
+            }
+            jobs.push(BuiltinJob {
+                name: "semver_checks",
+                entry: semver_checks,
+                install_commandline: semver_checks.install_commandline.clone(),
+                commandline: semver_checks.commandline.clone().unwrap_or(default_cmdline),
+                tools: default_tools(&semver_checks.tools, "cargo-semver-checks"),
+                artifacts: None,
+                after_success: None,
+                default_timeout: None,
+                // A release is the new baseline, so there's nothing
+                // to compare it with:
+                release_tags: false,
+            });
+        }
+        if self.canary {
+            let canary = self.canary_entry.the_entry();
+            jobs.push(BuiltinJob {
+                name: "canary",
+                entry: canary,
+                install_commandline: canary
+                    .install_commandline
+                    .clone()
+                    .or_else(|| self.test_runner_setup_commandline()),
+                commandline: canary.commandline.clone().unwrap_or_else(|| {
+                    format!(
+                        "{} && {}",
+                        self.test_runner_commandline(&self.test_commandline),
+                        self.clippy.commandline()
+                    )
+                }),
+                tools: self.test_runner_tools(),
+                artifacts: None,
+                after_success: None,
+                default_timeout: None,
+                // Release builds aren't scheduled:
+                release_tags: false,
+            });
+        }
+        for job in &mut jobs {
+            job.install_commandline = job.entry.with_rustup(job.install_commandline.take());
+        }
+        jobs
+    }
+
+    /// Returns the jobs that can be required by other jobs: the test
+    /// job and all matrix entries.
+    fn requirement_graph(&self) -> BTreeMap<&str, Requirement<'_>> {
+        let mut graph = BTreeMap::new();
+        graph.insert(
+            "test",
+            Requirement {
+                run: true,
+                run_cron: true,
+                requires: &self.test_requires,
+            },
+        );
+        for (name, entry) in self.matrix_entries() {
+            graph.insert(
+                name,
+                Requirement {
+                    run: entry.run,
+                    run_cron: entry.run_cron,
+                    requires: &entry.requires,
+                },
+            );
+        }
+        // Built-in jobs that don't run can't be required either:
+        for name in BUILTIN_JOB_NAMES {
+            graph.entry(*name).or_insert(Requirement {
+                run: false,
+                run_cron: false,
+                requires: &[],
+            });
+        }
+        graph
+    }
+
+    /// Checks the requirements between jobs and returns the depth of
+    /// each job in the requirement graph: Jobs without requirements
+    /// are at depth 0, all other jobs one deeper than their deepest
+    /// requirement.
+    fn requirement_depths(&self) -> Result<BTreeMap<&str, usize>, Error> {
+        let graph = self.requirement_graph();
+        for (name, job) in &graph {
+            for requirement in job.requires {
+                let required =
+                    graph
+                        .get(requirement.as_str())
+                        .ok_or_else(|| Error::UnknownRequirement {
+                            name: name.to_string(),
+                            requirement: requirement.to_string(),
+                        })?;
+                let cron_only = job.run_cron && !job.run;
+                if (job.run && !required.run) || (cron_only && !required.run_cron) {
+                    return Err(Error::DisabledRequirement {
+                        name: name.to_string(),
+                        requirement: requirement.to_string(),
+                    });
+                }
+            }
+        }
+        let mut depths = BTreeMap::new();
+        for name in graph.keys() {
+            requirement_depth(name, &graph, &mut depths, &mut vec![])?;
+        }
+        Ok(depths)
+    }
+
+    fn default_cache() -> String {
+        Self::default().cache
+    }
+
+    fn default_cache_version() -> String {
+        Self::default().cache_version
+    }
+
+    fn default_sccache_version() -> String {
+        Self::default().sccache_version
+    }
+
+    fn default_sanitizers() -> Vec<Sanitizer> {
+        Self::default().sanitizers
+    }
+
+    fn default_has_lib_targets() -> bool {
+        Self::default().has_lib_targets
+    }
+
+    fn default_canary_entry() -> CanaryEntry {
+        Self::default().canary_entry
+    }
+
+    fn default_docs_rustdocflags() -> String {
+        Self::default().docs_rustdocflags
+    }
+
+    fn default_os() -> Vec<OsEntry> {
+        Self::default().os
+    }
+
+    fn default_dist() -> String {
+        Self::default().dist
+    }
+
+    fn default_versions() -> Vec<String> {
+        Self::default().versions
+    }
+
+    fn default_test_commandline() -> String {
+        Self::default().test_commandline
+    }
+
+    fn default_test_schedule() -> String {
+        Self::default().test_schedule
+    }
+
+    fn default_scheduled_test_branches() -> Vec<String> {
+        Self::default().scheduled_test_branches
+    }
+
+    fn default_executors() -> Vec<ExecutorEntry> {
+        Self::default().executors
+    }
+
+    fn default_msrv_commandline() -> String {
+        Self::default().msrv_commandline
+    }
+}
+
+impl TemplateCIConfig {
+    /// Collects per-member test jobs and custom matrix entries from
+    /// the template-ci metadata of all workspace members except the
+    /// root package. Member entries get prefixed with the member's
+    /// name and run in the member's directory.
+    fn merge_workspace_members(
+        &mut self,
+        metadata: &cargo_metadata::Metadata,
+    ) -> Result<(), Error> {
+        #[derive(Debug, Deserialize)]
+        struct Metadata {
+            #[serde(default)]
+            template_ci: Option<MemberConfig>,
+        }
+        let root_id = metadata.root_package().map(|package| &package.id);
+        let packages = metadata.workspace_packages();
+        let dirs: HashMap<&str, String> = packages
+            .iter()
+            .map(|package| {
+                let dir = package
+                    .manifest_path
+                    .parent()
+                    .and_then(|dir| dir.strip_prefix(&metadata.workspace_root).ok())
+                    .map(|dir| dir.to_string())
+                    .unwrap_or_default();
+                (package.name.as_str(), dir)
+            })
+            .collect();
+        let dependencies: HashMap<&str, Vec<&str>> = packages
+            .iter()
+            .map(|package| {
+                let deps = package
+                    .dependencies
+                    .iter()
+                    .filter(|dep| dep.path.is_some() && dirs.contains_key(dep.name.as_str()))
+                    .map(|dep| dep.name.as_str())
+                    .collect();
+                (package.name.as_str(), deps)
+            })
+            .collect();
+        for package in packages {
+            if Some(&package.id) == root_id || package.metadata.is_null() {
+                continue;
+            }
+            let md: Metadata = serde_json::from_value(package.metadata.clone())?;
+            let member = match md.template_ci {
+                Some(member) => member,
+                None => continue,
+            };
+            if member.member_test {
+                self.member_tests.push(MemberTest {
+                    name: package.name.clone(),
+                    commandline: member
+                        .member_test_commandline
+                        .unwrap_or_else(|| format!("cargo test --verbose -p {}", package.name)),
+                    dir: dirs[package.name.as_str()].clone(),
+                    has_lib_targets: has_lib_target(package),
+                    paths: workspace_dependencies(&package.name, &dependencies)
+                        .into_iter()
+                        .map(|name| dirs[name].clone())
+                        .collect(),
+                });
+            }
+            let member_dir = &dirs[package.name.as_str()];
+            for (entry_name, mut entry) in member.additional_matrix_entries {
+                let name = format!("{}_{}", package.name.replace('-', "_"), entry_name);
+                if self.additional_matrix_entries.contains_key(&name) {
+                    return Err(Error::DuplicateEntry { name });
+                }
+                if let Some(commandline) = &mut entry.0.commandline {
+                    if !member_dir.is_empty() {
+                        *commandline = format!("cd {} && {}", member_dir, commandline);
+                    }
+                }
+                self.additional_matrix_entries.insert(name, entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether member test jobs get skipped when a change
+    /// does not affect the member.
+    pub(crate) fn detects_changes(&self) -> bool {
+        self.change_detection.run && !self.member_tests.is_empty()
+    }
+
+    /// Returns the sum of the timeouts of all matrix entries that run
+    /// and have one, or None if no entry has a timeout. Some built-in
+    /// entries have a default timeout.
+    pub(crate) fn total_timeout(&self) -> Option<Duration> {
+        let builtin_timeouts = self
+            .builtin_jobs()
+            .iter()
+            .map(MatrixEntryExt::duration)
+            .collect::<Vec<_>>();
+        vec![&self.rustfmt.0, &self.clippy.0, &self.bench.0]
+            .into_iter()
+            .chain(
+                self.additional_matrix_entries
+                    .values()
+                    .map(|entry| entry.the_entry()),
+            )
+            .filter(|entry| entry.run || entry.run_cron)
+            .map(|entry| entry.timeout)
+            .chain(builtin_timeouts)
+            .flatten()
+            .fold(None, |total, timeout| {
+                Some(total.unwrap_or_default() + timeout)
+            })
+    }
+
+    /// Returns true if any job requires other jobs.
+    pub(crate) fn uses_stages(&self) -> bool {
+        self.requirement_graph()
+            .values()
+            .any(|job| !job.requires.is_empty())
+    }
+
+    /// Returns the depth of a job in the requirement graph. Jobs that
+    /// can't be required (like the MSRV check) are as deep as the
+    /// test job.
+    pub(crate) fn stage(&self, name: &str) -> usize {
+        self.stages
+            .get(name)
+            .or_else(|| self.stages.get("test"))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Returns the deepest stage in the requirement graph.
+    pub(crate) fn last_stage(&self) -> usize {
+        self.stages.values().copied().max().unwrap_or(0)
+    }
+
+    /// Returns the CircleCI job names of the jobs that a job requires.
+    /// Requiring `test` means requiring the test jobs of every version,
+    /// workspace member and the pinned toolchain.
+    pub(crate) fn required_jobs(&self, name: &str) -> Vec<String> {
+        self.required_jobs_where(name, |job| job.run)
+    }
+
+    /// Returns the CircleCI job names of the jobs that a job requires
+    /// in the scheduled workflow, leaving out the jobs that don't run
+    /// there.
+    pub(crate) fn scheduled_required_jobs(&self, name: &str) -> Vec<String> {
+        self.required_jobs_where(name, |job| job.run_cron)
+    }
+
+    fn required_jobs_where(&self, name: &str, runs: impl Fn(&Requirement) -> bool) -> Vec<String> {
+        let graph = self.requirement_graph();
+        let job = match graph.get(name) {
+            Some(job) => job,
+            None => return vec![],
+        };
+        let mut names = vec![];
+        for requirement in job.requires {
+            if !graph.get(requirement.as_str()).map(&runs).unwrap_or(false) {
+                continue;
+            }
+            if requirement == "test" {
+                for version in self.test_versions() {
+                    for os in &self.os {
+                        names.push(format!("test-{}{}", version, self.os_suffix(os)));
+                    }
+                }
+                for member in &self.member_tests {
+                    for (version, _) in self.member_test_versions() {
+                        names.push(format!("{}-{}", member.job_name(), version));
+                    }
+                }
+                if self.pinned_toolchain().is_some() {
+                    names.push("test_pinned".to_string());
+                }
+                continue;
+            }
+            if let Some((_, entry)) = self
+                .matrix_entries()
+                .into_iter()
+                .find(|(entry_name, _)| entry_name == requirement)
+            {
+                for os in self.entry_oses(entry) {
+                    names.push(format!("{}{}", requirement, self.os_suffix(os)));
+                }
+            }
+        }
+        names
+    }
+
+    /// Returns the command line for the workspace-wide test job,
+    /// which leaves out the members that have their own test jobs.
+    pub(crate) fn root_test_commandline(&self) -> String {
+        let mut cmdline = self.test_runner_commandline(&self.test_commandline);
+        let whole_workspace = cmdline
+            .split_whitespace()
+            .any(|arg| arg == "--all" || arg == "--workspace");
+        if whole_workspace {
+            for member in &self.member_tests {
+                cmdline.push_str(" --exclude ");
+                cmdline.push_str(&member.name);
+            }
+        }
+        cmdline
+    }
+
+    /// Returns a test command line for the configured test runner:
+    /// With nextest, `cargo test` gets replaced by `cargo nextest run`
+    /// with the `ci` profile, which writes a JUnit report.
+    pub(crate) fn test_runner_commandline(&self, cmdline: &str) -> String {
+        match (self.test_runner, cmdline.strip_prefix("cargo test")) {
+            (TestRunner::Nextest, Some(args)) => format!("cargo nextest run --profile ci{}", args),
+            _ => cmdline.to_string(),
+        }
+    }
+
+    /// Returns the tools that the test runner needs in addition to
+    /// the global ones.
+    fn test_runner_tools(&self) -> Vec<&'static str> {
+        match self.test_runner {
+            TestRunner::Cargo => vec![],
+            TestRunner::Nextest => default_tools(&self.tools, "cargo-nextest"),
+        }
+    }
+
+    /// Returns the command line that sets up the test runner, if it
+    /// needs any setup: For nextest, it adds a `ci` profile that
+    /// writes a JUnit report unless the nextest config already has
+    /// one.
+    pub(crate) fn test_runner_setup_commandline(&self) -> Option<String> {
+        match self.test_runner {
+            TestRunner::Cargo => None,
+            TestRunner::Nextest => Some(
+                concat!(
+                    "mkdir -p .config && ",
+                    r#"{ grep -qs "^\[profile\.ci" .config/nextest.toml || "#,
+                    r#"printf "\n[profile.ci.junit]\npath = \"junit.xml\"\n" >> .config/nextest.toml; }"#
+                )
+                .to_string(),
+            ),
+        }
+    }
+
+    /// Returns the command line that installs and sets up the test
+    /// runner on travis, where the global tools are already
+    /// installed.
+    pub(crate) fn test_runner_install_commandline(&self) -> Option<String> {
+        let steps: Vec<String> = self
+            .tools_commandline(self.test_runner_tools().into_iter())
+            .into_iter()
+            .chain(self.test_runner_setup_commandline())
+            .collect();
+        if steps.is_empty() {
+            None
+        } else {
+            Some(steps.join(" && "))
+        }
+    }
+
+    /// Returns the command line of the workspace-wide test job, split
+    /// across containers if it has a `test_parallelism`.
+    pub(crate) fn test_job_commandline(&self) -> String {
+        // The members with their own test jobs run their integration
+        // tests there:
+        let member_dirs: Vec<&str> = self
+            .member_tests
+            .iter()
+            .map(|member| member.dir.as_str())
+            .collect();
+        self.split_commandline(
+            &self.root_test_commandline(),
+            self.test_parallelism,
+            "**",
+            &member_dirs,
+            self.has_lib_targets,
+        )
+    }
+
+    /// Returns the command line of a custom matrix entry, split across
+    /// containers if it has a `parallelism`.
+    pub(crate) fn entry_commandline(&self, entry: &MatrixEntry) -> String {
+        self.parallel_commandline(
+            entry.commandline.as_deref().unwrap_or_default(),
+            entry.parallelism,
+        )
+    }
+
+    /// Returns a command line that only runs this CircleCI container's
+    /// share of the tests if `parallelism` is set. nextest runs its
+    /// own partition of the tests; `cargo test` runs a share of the
+    /// integration test files, split by name with `circleci tests
+    /// split`, and the first container also runs the unit and doc
+    /// tests. Other command lines, including `cargo test` command
+    /// lines that pick their own test targets, can't get split, and
+    /// `validate` rejects a `parallelism` for them.
+    pub(crate) fn parallel_commandline(&self, cmdline: &str, parallelism: Option<u32>) -> String {
+        self.split_commandline(cmdline, parallelism, "**", &[], self.has_lib_targets)
+    }
+
+    /// Returns the command line of a workspace member's test job,
+    /// split across containers like the root test job, but only
+    /// running the member's own integration tests.
+    pub(crate) fn member_test_commandline(&self, member: &MemberTest) -> String {
+        let dir = if member.dir.is_empty() {
+            "."
+        } else {
+            member.dir.as_str()
+        };
+        self.split_commandline(
+            &self.test_runner_commandline(&member.commandline),
+            self.test_parallelism,
+            dir,
+            &[],
+            member.has_lib_targets,
+        )
+    }
+
+    /// Splits a test command line across containers, running the
+    /// integration tests in the `tests` directories under the
+    /// `tests_parent` glob, except for the ones in `excluded_dirs`.
+    /// Integration tests are either `tests/*.rs` or `tests/*/main.rs`.
+    fn split_commandline(
+        &self,
+        cmdline: &str,
+        parallelism: Option<u32>,
+        tests_parent: &str,
+        excluded_dirs: &[&str],
+        has_lib_targets: bool,
+    ) -> String {
+        if parallelism.is_none() || !splits_commandline(cmdline) {
+            return cmdline.to_string();
+        }
+        if let Some(args) = cmdline.strip_prefix("cargo nextest run") {
+            return format!(
+                "cargo nextest run --partition count:$((CIRCLE_NODE_INDEX + 1))/$CIRCLE_NODE_TOTAL{}",
+                args
+            );
+        }
+        let args = cmdline.strip_prefix("cargo test").unwrap_or_default();
+        let first_container = if has_lib_targets {
+            format!("cargo test --lib --bins{0} && cargo test --doc{0}", args)
+        } else {
+            format!("cargo test --bins{}", args)
+        };
+        let exclusion = if excluded_dirs.is_empty() {
+            String::new()
+        } else {
+            let dirs: Vec<String> = excluded_dirs.iter().map(|dir| escape_regex(dir)).collect();
+            format!(r#"| sed -E "\,^({})/,d" "#, dirs.join("|"))
+        };
+        format!(
+            concat!(
+                r#"tests=$(circleci tests glob "{0}/tests/*.rs" "{0}/tests/*/main.rs" {1}"#,
+                "| circleci tests split --split-by=name ",
+                r#"| sed -E "s|^(.*/)?tests/([^/]*)(/main)?\.rs|--test \2|"); "#,
+                r#"if [[ "$CIRCLE_NODE_INDEX" == 0 ]]; then {2}; fi && "#,
+                r#"if [[ -n "$tests" ]]; then cargo test $tests{3}; fi"#
+            ),
+            tests_parent, exclusion, first_container, args
+        )
+    }
+
+    /// Returns true if any job installs tools.
+    pub(crate) fn uses_tools(&self) -> bool {
+        !self.all_tools().is_empty()
+    }
+
+    /// Returns the tools that any job installs, sorted and without
+    /// duplicates.
+    pub(crate) fn all_tools(&self) -> Vec<&str> {
+        let tools: BTreeSet<&str> = self
+            .tools
+            .iter()
+            .chain(
+                self.matrix_entries()
+                    .into_iter()
+                    .flat_map(|(_, entry)| &entry.tools),
+            )
+            .map(String::as_str)
+            .chain(self.builtin_jobs().into_iter().flat_map(|job| job.tools))
+            .chain(self.test_runner_tools())
+            .collect();
+        tools.into_iter().collect()
+    }
+
+    /// Returns the directory that tools get installed into.
+    pub(crate) fn tools_dir(&self) -> &'static str {
+        TOOLS_DIR
+    }
+
+    /// Returns the directory that installed tools are in, which has
+    /// to be on the `PATH`.
+    pub(crate) fn tools_bin_dir(&self) -> String {
+        format!("{}/bin", TOOLS_DIR)
+    }
+
+    /// Returns the command line that installs the tools every job
+    /// but rustfmt needs.
+    pub(crate) fn global_tools_commandline(&self) -> Option<String> {
+        self.tools_commandline(self.tools.iter().map(String::as_str))
+    }
+
+    /// Returns the command line that installs all the tools the test
+    /// jobs need: the global ones and the test runner's.
+    pub(crate) fn test_tools_commandline(&self) -> Option<String> {
+        self.tools_commandline(
+            self.tools
+                .iter()
+                .map(String::as_str)
+                .chain(self.test_runner_tools()),
+        )
+    }
+
+    /// Returns the command line that installs the tools that only a
+    /// matrix entry needs.
+    pub(crate) fn entry_tools_commandline(&self, entry: &MatrixEntry) -> Option<String> {
+        self.tools_commandline(entry.tools.iter().map(String::as_str))
+    }
+
+    /// Returns the command line that installs all the tools a matrix
+    /// entry's job needs.
+    pub(crate) fn job_tools_commandline(&self, entry: &MatrixEntry) -> Option<String> {
+        self.tools_commandline(self.tools.iter().chain(&entry.tools).map(String::as_str))
+    }
+
+    /// Returns the command line that installs the tools a built-in
+    /// job needs by default, which travis installs in addition to the
+    /// entry's tools.
+    pub(crate) fn builtin_tools_commandline(&self, job: &BuiltinJob) -> Option<String> {
+        self.tools_commandline(job.tools.iter().copied())
+    }
+
+    /// Returns the command line that installs all the tools a
+    /// built-in job needs.
+    pub(crate) fn builtin_job_tools_commandline(&self, job: &BuiltinJob) -> Option<String> {
+        self.tools_commandline(
+            self.tools
+                .iter()
+                .chain(&job.entry.tools)
+                .map(String::as_str)
+                .chain(job.tools.iter().copied()),
+        )
+    }
+
+    /// Returns the command line that installs tools into the cached
+    /// `TOOLS_DIR` with the `TOOLS_TOOLCHAIN`. Tools that are already
+    /// installed in the requested version don't get installed again.
+    fn tools_commandline<'a>(&self, tools: impl Iterator<Item = &'a str>) -> Option<String> {
+        let mut unique = vec![];
+        for tool in tools {
+            if !unique.contains(&tool) {
+                unique.push(tool);
+            }
+        }
+        let tools = unique;
+        if tools.is_empty() {
+            return None;
+        }
+        let toolchain = format!(
+            "rustup toolchain install {} --profile minimal && ",
+            TOOLS_TOOLCHAIN
+        );
+        Some(match self.tool_installer {
+            ToolInstaller::Cargo => format!(
+                r#"{}cargo +{} install --locked --root "{}" {}"#,
+                toolchain,
+                TOOLS_TOOLCHAIN,
+                TOOLS_DIR,
+                tools.join(" ")
+            ),
+            ToolInstaller::Binstall => format!(
+                concat!(
+                    "{}{{ command -v cargo-binstall > /dev/null || ",
+                    "curl -LsSf https://raw.githubusercontent.com/cargo-bins/cargo-binstall/main/install-from-binstall-release.sh | bash; }} && ",
+                    r#"cargo +{} binstall --no-confirm --root "{}" {}"#
+                ),
+                toolchain,
+                TOOLS_TOOLCHAIN,
+                TOOLS_DIR,
+                tools.join(" ")
+            ),
+        })
+    }
+
+    /// Returns the path of the JUnit report that the test jobs write.
+    pub(crate) fn test_results(&self) -> Option<&'static str> {
+        match self.test_runner {
+            TestRunner::Cargo => None,
+            TestRunner::Nextest => Some(NEXTEST_JUNIT_PATH),
+        }
+    }
+
+    /// Returns the OSes that a matrix entry runs on.
+    pub(crate) fn entry_oses(&self, entry: &MatrixEntry) -> Vec<&OsEntry> {
+        if entry.os.is_empty() {
+            return self.os.iter().take(1).collect();
+        }
+        self.os
+            .iter()
+            .filter(|os| entry.os.contains(&os.name))
+            .collect()
+    }
+
+    /// Returns the suffix that distinguishes the names of jobs
+    /// running on the given OS from those on the primary OS.
+    pub(crate) fn os_suffix(&self, os: &OsEntry) -> String {
+        if self.os.first() == Some(os) {
+            String::new()
+        } else {
+            format!("-{}", os.name)
+        }
+    }
+
+    /// Returns true if jobs cache the cargo registry and either the
+    /// target directory or the sccache directory.
+    pub(crate) fn caches_builds(&self) -> bool {
+        self.cache == "cargo" || self.uses_sccache()
+    }
+
+    /// Returns true if jobs compile through sccache.
+    pub(crate) fn uses_sccache(&self) -> bool {
+        self.cache == "sccache"
+    }
+
+    /// Returns the command line that downloads the sccache release
+    /// for the OS a job runs on into cargo's bin directory.
+    pub(crate) fn sccache_install_commandline(&self) -> String {
+        let release = format!("sccache-v{}-$triple", self.sccache_version);
+        format!(
+            concat!(
+                "arch=$(uname -m | sed s/arm64/aarch64/); ",
+                "case $(uname -s) in ",
+                "Linux) triple=$arch-unknown-linux-musl;; ",
+                "Darwin) triple=$arch-apple-darwin;; ",
+                "*) triple=x86_64-pc-windows-msvc;; ",
+                "esac; ",
+                "mkdir -p /tmp/sccache && ",
+                "curl -sSfL https://github.com/mozilla/sccache/releases/download/v{version}/{release}.tar.gz ",
+                "| tar xz -C /tmp/sccache && ",
+                "cp /tmp/sccache/{release}/sccache* ${{CARGO_HOME:-$HOME/.cargo}}/bin/"
+            ),
+            version = self.sccache_version,
+            release = release,
+        )
+    }
+
+    /// Returns true if any CircleCI job needs to install rust itself.
+    pub(crate) fn installs_rust(&self) -> bool {
+        self.os.iter().any(|os| !os.uses_docker())
+    }
+
+    /// Returns the linux distribution that travis runs on.
+    pub(crate) fn linux_dist(&self) -> &str {
+        self.os
+            .iter()
+            .find(|os| os.name == "linux")
+            .and_then(|os| os.dist.as_deref_option())
+            .unwrap_or(&self.dist)
+    }
+
+    /// Returns the macOS image that travis runs on, if configured.
+    pub(crate) fn osx_image(&self) -> Option<&str> {
+        self.os
+            .iter()
+            .find(|os| os.name == "macos")
+            .and_then(|os| os.image.as_deref_option())
+    }
+
+    /// Returns the toolchain from the repo's toolchain file if
+    /// generated test jobs should run on it.
+    pub(crate) fn pinned_toolchain(&self) -> Option<&Toolchain> {
+        match self.toolchain_file {
+            ToolchainFileMode::Override => None,
+            ToolchainFileMode::Matrix | ToolchainFileMode::Respect => self.toolchain.as_ref(),
+        }
+    }
+
+    /// Returns true if test jobs should run on the configured
+    /// versions, ignoring (and removing) the toolchain file.
+    pub(crate) fn overrides_toolchain(&self) -> bool {
+        self.toolchain_file != ToolchainFileMode::Respect || self.toolchain.is_none()
+    }
+
+    /// Returns the versions that test jobs with an overridden
+    /// toolchain run on.
+    pub(crate) fn test_versions(&self) -> &[String] {
+        if self.overrides_toolchain() {
+            &self.versions
+        } else {
+            &[]
+        }
+    }
+
+    /// Returns the versions that the test jobs of workspace members
+    /// run on, and whether each one is the pinned toolchain.
+    pub(crate) fn member_test_versions(&self) -> Vec<(&str, bool)> {
+        let mut versions: Vec<(&str, bool)> = self
+            .test_versions()
+            .iter()
+            .map(|version| (version.as_str(), false))
+            .collect();
+        if let Some(toolchain) = self.pinned_toolchain() {
+            versions.push((toolchain.channel.as_str(), true));
+        }
+        versions
+    }
+
+    /// Returns the nightly versions pinned to a date, like
+    /// `nightly-2020-01-01`, that any job runs on.
+    pub(crate) fn pinned_nightlies(&self) -> BTreeSet<&str> {
+        self.test_versions()
+            .iter()
+            .map(String::as_str)
+            .chain(
+                self.matrix_entries()
+                    .into_iter()
+                    .map(|(_, entry)| entry.version.as_str()),
+            )
+            .chain(Some(self.features.version.as_str()))
+            .chain(self.targets.iter().map(|target| target.version.as_str()))
+            .filter(|version| nightly::pinned_date(version).is_some())
+            .collect()
+    }
+}
+
+/// Parses a numeric rust version like `1.40` or `1.40.0` into a
+/// comparable tuple. Returns None for channel names like `stable`.
+fn numeric_version(version: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = version.split('.').map(str::parse::<u64>);
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+    let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major, minor, patch))
+}
+
+impl TemplateCIConfig {
+    /// Returns the name of the CircleCI executor for jobs that don't
+    /// test on a particular version: the first configured executor.
+    pub(crate) fn default_executor(&self) -> &str {
+        self.executors
+            .iter()
+            .chain(self.additional_executors.iter())
+            .next()
+            .map_or("stable", |executor| executor.name.as_str())
+    }
+
+    pub(crate) fn all_executors_for_circleci(&self) -> impl Serialize {
+        #[derive(Debug, Serialize)]
+        struct DockerCfg {
+            image: String,
+        }
+        #[derive(Debug, Serialize)]
+        struct MacosCfg {
+            xcode: String,
+        }
+        #[derive(Debug, Serialize)]
+        struct MachineCfg {
+            image: String,
+        }
+        #[derive(Debug, Serialize, Default)]
+        struct Entry {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            docker: Option<Vec<DockerCfg>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            macos: Option<MacosCfg>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            machine: Option<MachineCfg>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            resource_class: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            shell: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            environment: Option<BTreeMap<&'static str, String>>,
+        }
+        impl Entry {
+            fn docker(image: String) -> Entry {
+                Entry {
+                    docker: Some(vec![DockerCfg { image }]),
+                    ..Default::default()
+                }
+            }
+
+            /// Returns an executor on the rustup image of `channel`
+            /// that runs `toolchain` instead, which rustup installs
+            /// on first use. This also overrides toolchain files.
+            fn toolchain(channel: &str, toolchain: &str) -> Entry {
+                Entry {
+                    environment: Some(
+                        vec![("RUSTUP_TOOLCHAIN", toolchain.to_string())]
+                            .into_iter()
+                            .collect(),
+                    ),
+                    ..Entry::docker(format!(
+                        "{}:{}",
+                        ExecutorEntry::default().image_name,
+                        channel
+                    ))
+                }
+            }
+        }
+
+        let all_executors = self
+            .executors
+            .iter()
+            .chain(self.additional_executors.iter());
+
+        let mut entry: BTreeMap<String, Entry> = all_executors
+            .map(|e| (e.name.clone(), Entry::docker(e.image_name.to_string())))
+            .collect();
+        // There are no images for every rust version, and a
+        // toolchain file would override the image's toolchain anyway:
+        if let Some(msrv) = self.msrv.as_deref_option() {
+            entry
+                .entry(msrv.to_string())
+                .or_insert_with(|| Entry::toolchain("stable", msrv));
+        }
+        for version in self.pinned_nightlies() {
+            entry
+                .entry(version.to_string())
+                .or_insert_with(|| Entry::toolchain(nightly::CHANNEL, version));
+        }
+        // The canary runs on its version even if the configured
+        // executors don't include it:
+        if self.canary {
+            let version = &self.canary_entry.the_entry().version;
+            entry
+                .entry(version.to_string())
+                .or_insert_with(|| Entry::toolchain(nightly::CHANNEL, version));
+        }
+        for os in self.os.iter().filter(|os| !os.uses_docker()) {
+            let executor = match os.name.as_str() {
+                "macos" => Entry {
+                    macos: Some(MacosCfg {
+                        xcode: os.image.clone().unwrap_or_else(|| "14.2.0".to_string()),
+                    }),
+                    ..Default::default()
+                },
+                "windows" => Entry {
+                    machine: Some(MachineCfg {
+                        image: os
+                            .image
+                            .clone()
+                            .unwrap_or_else(|| "windows-server-2022-gui:current".to_string()),
+                    }),
+                    resource_class: Some("windows.medium".to_string()),
+                    shell: Some("bash.exe".to_string()),
+                    ..Default::default()
+                },
+                _ => Entry {
+                    machine: Some(MachineCfg {
+                        image: os.image.clone().unwrap_or_default(),
+                    }),
+                    ..Default::default()
+                },
+            };
+            entry.insert(os.name.clone(), executor);
+        }
+        entry
+    }
+}
+
+custom_error! {pub Error
+               Cargo{source: cargo_metadata::Error} = "Could not get cargo metadata",
+               Deserialization{source: serde_json::Error} = "Could not parse cargo metadata",
+               TOMLDeserialization{source: toml::de::Error} = "Could not parse TOML configuration file",
+               IO{source: io::Error} = "IO",
+               BadMsrv{msrv: String} = "MSRV {msrv:?} is not a rust version number",
+               Toolchain{source: toolchain::Error} = "Could not read the rust-toolchain file: {source}",
+               UnknownFeature{feature: String} = "Feature {feature:?} is not defined by the package",
+               NoOs = "The OS matrix must contain at least one OS",
+               UnknownOs{os: String} = "Unknown OS {os:?}: Use \"linux\", \"macos\" or \"windows\"",
+               EntryOsNotInMatrix{entry: String, os: String} = "Entry {entry:?} runs on OS {os:?}, which is not in the OS matrix",
+               DuplicateEntry{name: String} = "Workspace member entry {name:?} collides with another matrix entry",
+               MissingCommandline{entry: String} = "Matrix entry {entry:?} needs a commandline",
+               UnsplittableCommandline{entry: String} = "Matrix entry {entry:?} sets a parallelism, but its commandline can't be split across containers",
+               MsrvTooNew{msrv: String, version: String} = "MSRV {msrv} is newer than the tested rust version {version}",
+               UnknownRequirement{name: String, requirement: String} = "{name:?} requires {requirement:?}, which is not a matrix entry",
+               DisabledRequirement{name: String, requirement: String} = "{name:?} requires {requirement:?}, which does not run in the same builds",
+               RequirementCycle{cycle: String} = "Jobs require each other in a cycle: {cycle}",
+               ReleaseWithoutPackage = "Releases need a package to publish, but the workspace has no root package",
+}
+
+#[cfg(test)]
+mod tests {
+    use custom_error::*;
+    use std::fs::{create_dir_all, File};
+    use std::io;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::{
+        read_metadata, ChangeDetection, FeatureCombinations, FeaturesConfig, MatrixEntryExt,
+        MemberTest, OsEntry, TemplateCIConfig, TestRunner, ToolInstaller,
+    };
+    use crate::toolchain::{Toolchain, ToolchainFileMode};
 
     custom_error! {Error
-                   ConfigError{source: super::Error} = "configuration error",
+                   Config{source: super::Error} = "configuration error",
                    IO{source: io::Error} = "IO",
                    Tempfile{source: tempfile::PersistError} = "Test setup/teardown",
     }
@@ -362,7 +2644,7 @@ path = "/dev/null"
         let dir = tempfile::tempdir()?;
         {
             let f = create_cargo_file(&dir, "")?;
-            let _conf = TemplateCIConfig::from_manifest(Some(&f))?;
+            let _conf = TemplateCIConfig::from_manifest(&read_metadata(Some(&f))?)?;
         }
         Ok(())
     }
@@ -378,7 +2660,7 @@ path = "/dev/null"
 bar = "baz"
 "#,
             )?;
-            let _conf = TemplateCIConfig::from_manifest(Some(&f))?;
+            let _conf = TemplateCIConfig::from_manifest(&read_metadata(Some(&f))?)?;
         }
         Ok(())
     }
@@ -397,7 +2679,7 @@ commandline='echo "running custom tests"'
 timeout='40min'
 "#,
             )?;
-            let _conf = TemplateCIConfig::from_manifest(Some(&f))?;
+            let _conf = TemplateCIConfig::from_manifest(&read_metadata(Some(&f))?)?;
         }
         Ok(())
     }
@@ -414,7 +2696,7 @@ name = "custom_templated_run"
 commandline='echo "running custom tests"'
 "#,
             )?;
-            let _conf = TemplateCIConfig::from_manifest(Some(&f))?;
+            let _conf = TemplateCIConfig::from_manifest(&read_metadata(Some(&f))?)?;
         }
         Ok(())
     }
@@ -427,13 +2709,1002 @@ commandline='echo "running custom tests"'
                 &dir,
                 r#"
 [package.metadata.template_ci]
-os = "foo"
+os = "macos"
 "#,
             )?;
-            let (conf, _) = TemplateCIConfig::from_manifest(Some(&f))?;
-            assert_eq!(conf.os, "foo");
+            let (mut conf, _) = TemplateCIConfig::from_manifest(&read_metadata(Some(&f))?)?;
+            conf.validate()?;
+            assert_eq!(conf.os[0].name, "macos");
             assert_eq!(conf.dist, TemplateCIConfig::default().dist);
         }
         Ok(())
     }
+
+    #[test]
+    fn msrv_from_rust_version() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        {
+            let path = dir.path().join("Cargo.toml");
+            let f = File::create(&path)?;
+            writeln!(
+                &f,
+                r#"
+[package]
+name = "testing"
+version = "0.0.1"
+rust-version = "1.56"
+[lib]
+name = "foo"
+path = "/dev/null"
+"#
+            )?;
+            let (conf, _) = TemplateCIConfig::from_manifest(&read_metadata(Some(&path))?)?;
+            assert_eq!(conf.msrv, Some("1.56.0".to_string()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn feature_matrix_jobs() {
+        let features = FeaturesConfig {
+            run: true,
+            all_features: false,
+            combinations: FeatureCombinations::Powerset,
+            exclude: vec!["c".to_string()],
+            available: vec!["default", "a", "b", "c"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            ..Default::default()
+        };
+        features.validate().expect("all listed features exist");
+        let flags: Vec<String> = features.jobs().into_iter().map(|j| j.flags).collect();
+        assert_eq!(
+            flags,
+            vec![
+                "--no-default-features",
+                "--no-default-features --features a",
+                "--no-default-features --features b",
+                "--no-default-features --features a,b",
+            ]
+        );
+    }
+
+    #[test]
+    fn feature_matrix_unknown_feature() {
+        let features = FeaturesConfig {
+            run: true,
+            include: vec!["nope".to_string()],
+            available: vec!["a".to_string()],
+            ..Default::default()
+        };
+        match features.validate() {
+            Err(super::Error::UnknownFeature { feature }) => assert_eq!(feature, "nope"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn config_file_next_to_manifest() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        {
+            let f = create_cargo_file(&dir, "")?;
+            File::create(dir.path().join("template-ci.toml"))?.write_all(b"os = \"macos\"")?;
+            let (conf, root) = TemplateCIConfig::merged_configs(Some(&f))?;
+            assert_eq!(conf.os[0].name, "macos");
+            assert_eq!(root, dir.path());
+
+            // A broken config file doesn't fall back to the manifest:
+            File::create(dir.path().join("template-ci.toml"))?
+                .write_all(b"miri_test_filter = [\"foo\"]")?;
+            match TemplateCIConfig::merged_configs(Some(&f)) {
+                Err(super::Error::TOMLDeserialization { .. }) => {}
+                other => panic!("Expected a parse error, got {:?}", other),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn workspace_members() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        {
+            let root = dir.path();
+            File::create(root.join("Cargo.toml"))?.write_all(
+                br#"
+[workspace]
+members = ["crates/*"]
+
+[workspace.metadata.template_ci]
+versions = ["stable"]
+"#,
+            )?;
+            for (name, extra) in &[
+                (
+                    "foo-bar",
+                    r#"
+[dependencies]
+baz = { path = "../baz" }
+
+[package.metadata.template_ci]
+member_test = true
+
+[package.metadata.template_ci.additional_matrix_entries.integration]
+commandline = "cargo test --test integration"
+"#,
+                ),
+                ("baz", ""),
+            ] {
+                let member = root.join("crates").join(name);
+                create_dir_all(&member)?;
+                writeln!(
+                    File::create(member.join("Cargo.toml"))?,
+                    "[package]\nname = \"{}\"\nversion = \"0.0.1\"\n[lib]\npath = \"/dev/null\"\n{}",
+                    name,
+                    extra
+                )?;
+            }
+
+            let (conf, _) = TemplateCIConfig::merged_configs(Some(&root.join("Cargo.toml")))?;
+            assert_eq!(conf.versions, vec!["stable"]);
+            assert_eq!(
+                conf.member_tests,
+                vec![MemberTest {
+                    name: "foo-bar".to_string(),
+                    commandline: "cargo test --verbose -p foo-bar".to_string(),
+                    dir: "crates/foo-bar".to_string(),
+                    has_lib_targets: true,
+                    paths: vec!["crates/baz".to_string(), "crates/foo-bar".to_string()],
+                }]
+            );
+            let detection = ChangeDetection {
+                shared_paths: vec!["Cargo.lock".to_string(), ".circleci/".to_string()],
+                ..Default::default()
+            };
+            assert_eq!(
+                detection.affected_regex(&conf.member_tests[0]),
+                r"^(crates/baz/.*|crates/foo-bar/.*|Cargo\.lock|\.circleci/.*)$"
+            );
+            assert_eq!(
+                conf.root_test_commandline(),
+                "cargo test --verbose --all --exclude foo-bar"
+            );
+            let entry = &conf.additional_matrix_entries["foo_bar_integration"];
+            assert_eq!(
+                entry.commandline(),
+                "cd crates/foo-bar && cargo test --test integration"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn os_matrix_formats() {
+        let conf: TemplateCIConfig = toml::from_str(r#"os = "macos""#).expect("single OS");
+        assert_eq!(conf.os, vec![OsEntry::named("macos")]);
+
+        let mut conf: TemplateCIConfig = toml::from_str(
+            r#"
+os = ["linux", { name = "windows", image = "windows-server-2019-vs2019:stable" }]
+
+[rustfmt]
+os = ["linux"]
+"#,
+        )
+        .expect("OS list");
+        conf.validate().expect("valid OS matrix");
+        assert_eq!(conf.os[0], OsEntry::named("linux"));
+        assert_eq!(
+            conf.os[1].image.as_deref(),
+            Some("windows-server-2019-vs2019:stable")
+        );
+        assert!(!conf.os[1].uses_docker());
+        assert_eq!(conf.entry_oses(conf.rustfmt.the_entry()), vec![&conf.os[0]]);
+        assert_eq!(conf.entry_oses(conf.clippy.the_entry()), vec![&conf.os[0]]);
+        assert_eq!(conf.os_suffix(&conf.os[1]), "-windows");
+    }
+
+    #[test]
+    fn os_matrix_validation() {
+        let mut conf: TemplateCIConfig = toml::from_str(r#"os = ["linux", "beos"]"#).unwrap();
+        match conf.validate() {
+            Err(super::Error::UnknownOs { os }) => assert_eq!(os, "beos"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+
+        let mut conf: TemplateCIConfig = toml::from_str(
+            r#"
+os = ["linux"]
+[clippy]
+os = ["macos"]
+"#,
+        )
+        .unwrap();
+        match conf.validate() {
+            Err(super::Error::EntryOsNotInMatrix { entry, os }) => {
+                assert_eq!(entry, "clippy");
+                assert_eq!(os, "macos");
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sccache_cache() {
+        let conf: TemplateCIConfig = toml::from_str(r#"cache = "cargo""#).expect("cargo cache");
+        assert!(conf.caches_builds());
+        assert!(!conf.uses_sccache());
+
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+cache = "sccache"
+sccache_version = "0.8.0"
+"#,
+        )
+        .expect("sccache");
+        assert!(conf.caches_builds());
+        assert!(conf.uses_sccache());
+        assert!(conf
+            .sccache_install_commandline()
+            .contains("/download/v0.8.0/sccache-v0.8.0-$triple.tar.gz"));
+
+        let conf: TemplateCIConfig = toml::from_str(r#"cache = "false""#).expect("no cache");
+        assert!(!conf.caches_builds());
+    }
+
+    #[test]
+    fn target_commandlines() {
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+[[targets]]
+triple = "thumbv7em-none-eabihf"
+flags = "--no-default-features"
+
+[[targets]]
+triple = "aarch64-unknown-linux-gnu"
+command = "test"
+runner = "qemu-aarch64 -L /usr/aarch64-linux-gnu"
+"#,
+        )
+        .expect("targets should parse");
+        assert_eq!(
+            conf.targets[0].commandline(),
+            "cargo check --verbose --target thumbv7em-none-eabihf --no-default-features"
+        );
+        assert_eq!(
+            conf.targets[1].commandline(),
+            "CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_RUNNER=\"qemu-aarch64 -L /usr/aarch64-linux-gnu\" cargo test --verbose --target aarch64-unknown-linux-gnu"
+        );
+    }
+
+    #[test]
+    fn msrv_newer_than_versions() {
+        let mut conf = TemplateCIConfig {
+            msrv: Some("1.40".to_string()),
+            versions: vec!["1.39.0".to_string(), "stable".to_string()],
+            ..Default::default()
+        };
+        match conf.validate() {
+            Err(super::Error::MsrvTooNew { msrv, version }) => {
+                assert_eq!(msrv, "1.40");
+                assert_eq!(version, "1.39.0");
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+
+        let mut conf = TemplateCIConfig {
+            msrv: Some("1.40".to_string()),
+            versions: vec!["1.40.0".to_string(), "stable".to_string()],
+            ..Default::default()
+        };
+        conf.validate().expect("MSRV matches the oldest version");
+    }
+
+    #[test]
+    fn msrv_executor() {
+        let conf = TemplateCIConfig {
+            msrv: Some("1.40".to_string()),
+            ..Default::default()
+        };
+        let executors = serde_json::to_value(conf.all_executors_for_circleci()).expect("executors");
+        assert_eq!(
+            executors["1.40"],
+            serde_json::json!({
+                "docker": [{"image": "liuchong/rustup:stable"}],
+                "environment": {"RUSTUP_TOOLCHAIN": "1.40"},
+            })
+        );
+    }
+
+    #[test]
+    fn default_executor() {
+        assert_eq!(TemplateCIConfig::default().default_executor(), "stable");
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+[[executors]]
+name = "1.70"
+image_name = "rust:1.70"
+"#,
+        )
+        .expect("executors");
+        assert_eq!(conf.default_executor(), "1.70");
+    }
+
+    #[test]
+    fn pinned_nightlies() {
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+versions = ["stable", "nightly-2020-01-01"]
+
+[bench]
+version = "nightly-2020-02-02"
+
+[features]
+version = "nightly-2020-01-01"
+"#,
+        )
+        .expect("pinned nightlies");
+        assert_eq!(
+            conf.pinned_nightlies().into_iter().collect::<Vec<_>>(),
+            vec!["nightly-2020-01-01", "nightly-2020-02-02"]
+        );
+        let executors = serde_json::to_value(conf.all_executors_for_circleci()).expect("executors");
+        assert_eq!(
+            executors["nightly-2020-01-01"],
+            serde_json::json!({
+                "docker": [{"image": "liuchong/rustup:nightly"}],
+                "environment": {"RUSTUP_TOOLCHAIN": "nightly-2020-01-01"},
+            })
+        );
+        assert_eq!(
+            executors["nightly"],
+            serde_json::json!({"docker": [{"image": "liuchong/rustup:nightly"}]})
+        );
+    }
+
+    #[test]
+    fn requirements() {
+        let mut conf: TemplateCIConfig = toml::from_str(
+            r#"
+versions = ["stable", "nightly"]
+test_requires = ["rustfmt"]
+
+[additional_matrix_entries.integration]
+commandline = "cargo test --test integration"
+requires = ["test", "clippy"]
+run_cron = true
+"#,
+        )
+        .expect("requirements");
+        conf.validate().expect("valid requirements");
+        assert!(conf.uses_stages());
+        assert_eq!(conf.stage("rustfmt"), 0);
+        assert_eq!(conf.stage("test"), 1);
+        assert_eq!(conf.stage("msrv"), 1);
+        assert_eq!(conf.stage("integration"), 2);
+        assert_eq!(conf.last_stage(), 2);
+        assert_eq!(
+            conf.required_jobs("integration"),
+            vec!["test-stable", "test-nightly", "clippy"]
+        );
+        assert_eq!(
+            conf.scheduled_required_jobs("integration"),
+            vec!["test-stable", "test-nightly"]
+        );
+
+        conf.toolchain_file = ToolchainFileMode::Respect;
+        conf.toolchain = Some(Toolchain {
+            channel: "1.70.0".to_string(),
+            components: vec![],
+            targets: vec![],
+        });
+        conf.member_tests.push(MemberTest {
+            name: "foo".to_string(),
+            commandline: "cargo test -p foo".to_string(),
+            dir: "foo".to_string(),
+            has_lib_targets: true,
+            paths: vec!["foo".to_string()],
+        });
+        assert_eq!(
+            conf.required_jobs("integration"),
+            vec!["test-foo-1.70.0", "test_pinned", "clippy"]
+        );
+    }
+
+    #[test]
+    fn total_timeout() {
+        assert_eq!(TemplateCIConfig::default().total_timeout(), None);
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+[clippy]
+timeout = "5m"
+
+[bench]
+timeout = "1h"
+
+[additional_matrix_entries.integration]
+commandline = "cargo test --test integration"
+timeout = "10m"
+"#,
+        )
+        .expect("timeouts");
+        assert_eq!(conf.total_timeout(), Some(Duration::from_secs(15 * 60)));
+    }
+
+    #[test]
+    fn coverage_job() {
+        assert!(TemplateCIConfig::default().builtin_jobs().is_empty());
+
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+coverage_tool = "tarpaulin"
+coverage_format = "cobertura"
+
+[coverage]
+run = true
+components = []
+"#,
+        )
+        .expect("coverage");
+        let jobs = conf.builtin_jobs();
+        assert_eq!(jobs[0].name, "coverage");
+        assert_eq!(jobs[0].install_commandline, None);
+        assert_eq!(jobs[0].tools, vec!["cargo-tarpaulin"]);
+        assert_eq!(
+            jobs[0].commandline,
+            "mkdir -p target/coverage && cargo tarpaulin --workspace --out Xml --output-dir target/coverage"
+        );
+        assert_eq!(jobs[0].artifacts, Some("target/coverage"));
+
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+[coverage]
+run = true
+commandline = "cargo llvm-cov --html"
+tools = ["cargo-llvm-cov@0.5"]
+"#,
+        )
+        .expect("coverage with a custom commandline");
+        let jobs = conf.builtin_jobs();
+        assert_eq!(
+            jobs[0].install_commandline.as_deref(),
+            Some("rustup component add llvm-tools-preview")
+        );
+        assert_eq!(jobs[0].commandline, "cargo llvm-cov --html");
+        assert!(jobs[0].tools.is_empty());
+        assert_eq!(
+            conf.builtin_job_tools_commandline(&jobs[0]).as_deref(),
+            Some(
+                r#"rustup toolchain install stable --profile minimal && cargo +stable install --locked --root "$HOME/.cargo-tools" cargo-llvm-cov@0.5"#
+            )
+        );
+    }
+
+    #[test]
+    fn security_checks() {
+        let mut conf: TemplateCIConfig = toml::from_str(
+            r#"
+security_checks_scheduled_only = true
+
+[audit]
+run = true
+
+[deny]
+run = true
+commandline = "cargo deny check licenses"
+"#,
+        )
+        .expect("security checks");
+        conf.schedule_security_checks();
+        let jobs = conf.builtin_jobs();
+        let names: Vec<_> = jobs.iter().map(|job| job.name).collect();
+        assert_eq!(names, vec!["audit", "deny"]);
+        assert_eq!(jobs[0].install_commandline, None);
+        assert_eq!(jobs[0].tools, vec!["cargo-audit"]);
+        assert_eq!(jobs[0].commandline, "cargo audit");
+        assert_eq!(jobs[1].commandline, "cargo deny check licenses");
+        assert!(jobs.iter().all(|job| !job.entry.run && job.entry.run_cron));
+    }
+
+    #[test]
+    fn unsafe_code_jobs() {
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+miri_test_filter = "unsafe_"
+sanitizers = ["address", "thread"]
+
+[miri]
+run = true
+
+[sanitizer]
+run_cron = true
+"#,
+        )
+        .expect("miri and sanitizer");
+        let jobs = conf.builtin_jobs();
+        assert_eq!(jobs[0].name, "miri");
+        assert_eq!(jobs[0].entry.version, "nightly");
+        assert_eq!(jobs[0].commandline, "cargo miri test -- unsafe_");
+        assert_eq!(jobs[0].timeout().as_deref(), Some("3600s"));
+        assert_eq!(jobs[1].name, "sanitizer");
+        assert_eq!(
+            jobs[1].commandline,
+            concat!(
+                r#"RUSTFLAGS="-Zsanitizer=address" RUSTDOCFLAGS="-Zsanitizer=address" "#,
+                r#"cargo test -Zbuild-std --target $(rustc -vV | grep host | cut -d" " -f2) && "#,
+                r#"RUSTFLAGS="-Zsanitizer=thread" RUSTDOCFLAGS="-Zsanitizer=thread" "#,
+                r#"cargo test -Zbuild-std --target $(rustc -vV | grep host | cut -d" " -f2)"#,
+            )
+        );
+        assert_eq!(conf.total_timeout(), Some(Duration::from_secs(90 * 60)));
+    }
+
+    #[test]
+    fn docs_job() {
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+[docs]
+run = true
+"#,
+        )
+        .expect("docs");
+        let jobs = conf.builtin_jobs();
+        assert_eq!(
+            jobs[0].commandline,
+            r#"RUSTDOCFLAGS="-D warnings" cargo doc --no-deps --all-features"#
+        );
+        assert_eq!(jobs[0].install_commandline, None);
+        assert_eq!(jobs[0].artifacts, Some("target/doc"));
+
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+docs_rustdocflags = ""
+docs_test = true
+
+[docs]
+run = true
+"#,
+        )
+        .expect("docs with doc tests");
+        assert_eq!(
+            conf.builtin_jobs()[0].commandline,
+            "cargo doc --no-deps --all-features && cargo test --doc --all-features"
+        );
+    }
+
+    #[test]
+    fn release_commandline() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = create_cargo_file(&dir, "[package.metadata.template_ci.release]\nrun = true")?;
+        let (conf, _) = TemplateCIConfig::merged_configs(Some(&path))?;
+        assert_eq!(conf.release.package.as_deref(), Some("testing"));
+        assert_eq!(
+            conf.release.commandline("CIRCLE_TAG"),
+            concat!(
+                r#"if [[ -n "$CIRCLE_TAG" ]]; then "#,
+                "[[ -f Cargo.lock ]] || cargo generate-lockfile; ",
+                r#"version=$(cargo pkgid -p testing | sed -e "s/.*[#@:]//"); "#,
+                r#"if [[ "$CIRCLE_TAG" != "v$version" ]]; then "#,
+                r#"echo "Tag $CIRCLE_TAG does not match version $version"; exit 1; fi; "#,
+                "cargo publish; else cargo publish --dry-run; fi"
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn semver_checks_job() {
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+semver_checks_baseline = "v1.0.0"
+
+[semver_checks]
+run = true
+"#,
+        )
+        .expect("semver checks");
+        let jobs = conf.builtin_jobs();
+        assert_eq!(jobs[0].name, "semver_checks");
+        assert_eq!(
+            jobs[0].commandline,
+            "cargo semver-checks --baseline-rev v1.0.0"
+        );
+        assert!(!jobs[0].release_tags);
+    }
+
+    #[test]
+    fn canary_job() {
+        assert!(TemplateCIConfig::default().builtin_jobs().is_empty());
+
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+versions = ["stable", "nightly-2020-01-01"]
+canary = true
+
+[clippy]
+commandline = "cargo clippy --all-targets"
+"#,
+        )
+        .expect("canary");
+        let jobs = conf.builtin_jobs();
+        assert_eq!(jobs[0].name, "canary");
+        assert_eq!(jobs[0].entry.version, "nightly");
+        assert!(!jobs[0].entry.run);
+        assert!(jobs[0].entry.run_cron);
+        assert!(jobs[0].entry.allow_failure);
+        assert_eq!(
+            jobs[0].install_commandline.as_deref(),
+            Some("rustup component add clippy")
+        );
+        assert_eq!(
+            jobs[0].commandline,
+            "cargo test --verbose --all && cargo clippy --all-targets"
+        );
+        assert!(!conf.pinned_nightlies().contains("nightly"));
+        assert!(!jobs[0].release_tags);
+
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+canary = true
+test_runner = "nextest"
+
+[[executors]]
+name = "stable"
+image_name = "rust:latest"
+
+[canary_entry]
+run = true
+timeout = "2h"
+tools = ["cargo-hack"]
+"#,
+        )
+        .expect("configured canary");
+        let jobs = conf.builtin_jobs();
+        assert!(!jobs[0].entry.run);
+        assert!(jobs[0].entry.run_cron);
+        assert!(jobs[0].entry.allow_failure);
+        assert_eq!(jobs[0].timeout().as_deref(), Some("7200s"));
+        assert_eq!(
+            jobs[0].commandline,
+            "cargo nextest run --profile ci --verbose --all && cargo clippy -- -D warnings"
+        );
+        assert_eq!(
+            conf.builtin_job_tools_commandline(&jobs[0]).as_deref(),
+            Some(
+                r#"rustup toolchain install stable --profile minimal && cargo +stable install --locked --root "$HOME/.cargo-tools" cargo-hack cargo-nextest"#
+            )
+        );
+        assert!(jobs[0]
+            .install_commandline
+            .as_deref()
+            .expect("canary install")
+            .starts_with("rustup component add clippy && mkdir -p .config && "));
+        let executors = serde_json::to_value(conf.all_executors_for_circleci()).expect("executors");
+        assert_eq!(
+            executors["nightly"]["environment"]["RUSTUP_TOOLCHAIN"],
+            "nightly"
+        );
+    }
+
+    #[test]
+    fn nextest_runner() {
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+test_runner = "nextest"
+"#,
+        )
+        .expect("nextest");
+        assert_eq!(
+            conf.root_test_commandline(),
+            "cargo nextest run --profile ci --verbose --all"
+        );
+        assert_eq!(
+            conf.test_runner_commandline("cargo test --verbose -p foo"),
+            "cargo nextest run --profile ci --verbose -p foo"
+        );
+        assert_eq!(conf.test_runner_commandline("make test"), "make test");
+        assert_eq!(conf.test_results(), Some("target/nextest/ci/junit.xml"));
+        assert_eq!(conf.all_tools(), vec!["cargo-nextest"]);
+        assert_eq!(
+            conf.test_tools_commandline().as_deref(),
+            Some(
+                r#"rustup toolchain install stable --profile minimal && cargo +stable install --locked --root "$HOME/.cargo-tools" cargo-nextest"#
+            )
+        );
+        assert_eq!(conf.global_tools_commandline(), None);
+        assert!(conf
+            .test_runner_install_commandline()
+            .expect("nextest install")
+            .starts_with(r#"rustup toolchain install stable --profile minimal && cargo +stable install --locked --root "$HOME/.cargo-tools" cargo-nextest && mkdir -p .config && "#));
+        assert!(TemplateCIConfig::default()
+            .test_runner_install_commandline()
+            .is_none());
+    }
+
+    #[test]
+    fn parallel_commandlines() {
+        let mut conf = TemplateCIConfig::default();
+        assert_eq!(conf.test_job_commandline(), "cargo test --verbose --all");
+        conf.test_parallelism = Some(4);
+        assert_eq!(
+            conf.test_job_commandline(),
+            concat!(
+                r#"tests=$(circleci tests glob "**/tests/*.rs" "**/tests/*/main.rs" "#,
+                "| circleci tests split --split-by=name ",
+                r#"| sed -E "s|^(.*/)?tests/([^/]*)(/main)?\.rs|--test \2|"); "#,
+                r#"if [[ "$CIRCLE_NODE_INDEX" == 0 ]]; then "#,
+                "cargo test --lib --bins --verbose --all && cargo test --doc --verbose --all; fi && ",
+                r#"if [[ -n "$tests" ]]; then cargo test $tests --verbose --all; fi"#
+            )
+        );
+        conf.has_lib_targets = false;
+        assert!(conf
+            .test_job_commandline()
+            .contains(r#"then cargo test --bins --verbose --all; fi"#));
+
+        conf.test_runner = TestRunner::Nextest;
+        assert_eq!(
+            conf.test_job_commandline(),
+            "cargo nextest run --partition count:$((CIRCLE_NODE_INDEX + 1))/$CIRCLE_NODE_TOTAL --profile ci --verbose --all"
+        );
+        assert_eq!(conf.parallel_commandline("make test", Some(2)), "make test");
+        conf.test_runner = TestRunner::Cargo;
+        assert_eq!(
+            conf.parallel_commandline("cargo test --test it", Some(2)),
+            "cargo test --test it"
+        );
+
+        let member = MemberTest {
+            name: "foo".to_string(),
+            commandline: "cargo test -p foo".to_string(),
+            dir: "crates/foo".to_string(),
+            has_lib_targets: false,
+            paths: vec!["crates/foo".to_string()],
+        };
+        assert_eq!(
+            conf.member_test_commandline(&member),
+            concat!(
+                r#"tests=$(circleci tests glob "crates/foo/tests/*.rs" "crates/foo/tests/*/main.rs" "#,
+                "| circleci tests split --split-by=name ",
+                r#"| sed -E "s|^(.*/)?tests/([^/]*)(/main)?\.rs|--test \2|"); "#,
+                r#"if [[ "$CIRCLE_NODE_INDEX" == 0 ]]; then cargo test --bins -p foo; fi && "#,
+                r#"if [[ -n "$tests" ]]; then cargo test $tests -p foo; fi"#
+            )
+        );
+        conf.member_tests.push(member);
+        assert!(conf.test_job_commandline().contains(concat!(
+            r#""**/tests/*/main.rs" | sed -E "\,^(crates/foo)/,d" "#,
+            "| circleci tests split"
+        )));
+    }
+
+    #[test]
+    fn tools() {
+        let mut conf: TemplateCIConfig = toml::from_str(
+            r#"
+tools = ["cargo-nextest@0.9"]
+
+[clippy]
+tools = ["cargo-deny", "cargo-nextest@0.9"]
+"#,
+        )
+        .expect("tools");
+        assert_eq!(conf.all_tools(), vec!["cargo-deny", "cargo-nextest@0.9"]);
+        assert_eq!(
+            conf.global_tools_commandline().as_deref(),
+            Some(
+                r#"rustup toolchain install stable --profile minimal && cargo +stable install --locked --root "$HOME/.cargo-tools" cargo-nextest@0.9"#
+            )
+        );
+        assert_eq!(
+            conf.job_tools_commandline(conf.clippy.the_entry())
+                .as_deref(),
+            Some(
+                r#"rustup toolchain install stable --profile minimal && cargo +stable install --locked --root "$HOME/.cargo-tools" cargo-nextest@0.9 cargo-deny"#
+            )
+        );
+        assert_eq!(conf.entry_tools_commandline(conf.rustfmt.the_entry()), None);
+
+        conf.tool_installer = ToolInstaller::Binstall;
+        assert_eq!(
+            conf.entry_tools_commandline(conf.clippy.the_entry()).as_deref(),
+            Some(concat!(
+                "rustup toolchain install stable --profile minimal && ",
+                "{ command -v cargo-binstall > /dev/null || ",
+                "curl -LsSf https://raw.githubusercontent.com/cargo-bins/cargo-binstall/main/install-from-binstall-release.sh | bash; } && ",
+                r#"cargo +stable binstall --no-confirm --root "$HOME/.cargo-tools" cargo-deny cargo-nextest@0.9"#
+            ))
+        );
+        assert!(!TemplateCIConfig::default().uses_tools());
+    }
+
+    #[test]
+    fn components_and_targets() {
+        let conf = TemplateCIConfig::default();
+        assert_eq!(
+            conf.rustfmt.install_commandline().as_deref(),
+            Some("rustup component add rustfmt")
+        );
+        assert_eq!(
+            conf.clippy.install_commandline().as_deref(),
+            Some("rustup component add clippy")
+        );
+        assert_eq!(conf.bench.install_commandline(), None);
+
+        let conf: TemplateCIConfig = toml::from_str(
+            r#"
+[rustfmt]
+components = []
+
+[clippy]
+install_commandline = "cargo install cargo-hack --locked"
+
+[miri]
+run = true
+targets = ["wasm32-unknown-unknown"]
+
+[additional_matrix_entries.coverage_llvm]
+components = ["llvm-tools-preview"]
+targets = ["wasm32-wasi", "wasm32-unknown-unknown"]
+commandline = "true"
+"#,
+        )
+        .expect("components and targets");
+        assert_eq!(conf.rustfmt.install_commandline(), None);
+        assert_eq!(
+            conf.clippy.install_commandline().as_deref(),
+            Some("rustup component add clippy && cargo install cargo-hack --locked")
+        );
+        assert_eq!(
+            conf.builtin_jobs()[0].install_commandline.as_deref(),
+            Some(concat!(
+                "rustup component add miri rust-src && ",
+                "rustup target add wasm32-unknown-unknown && cargo miri setup"
+            ))
+        );
+        assert_eq!(
+            conf.additional_matrix_entries["coverage_llvm"]
+                .install_commandline()
+                .as_deref(),
+            Some(concat!(
+                "rustup component add llvm-tools-preview && ",
+                "rustup target add wasm32-wasi wasm32-unknown-unknown"
+            ))
+        );
+    }
+
+    #[test]
+    fn requirement_errors() {
+        let mut conf: TemplateCIConfig =
+            toml::from_str(r#"test_requires = ["nope"]"#).expect("unknown requirement");
+        match conf.validate() {
+            Err(super::Error::UnknownRequirement { name, requirement }) => {
+                assert_eq!(name, "test");
+                assert_eq!(requirement, "nope");
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+
+        let mut conf: TemplateCIConfig =
+            toml::from_str(r#"test_requires = ["bench"]"#).expect("disabled requirement");
+        match conf.validate() {
+            Err(super::Error::DisabledRequirement { name, requirement }) => {
+                assert_eq!(name, "test");
+                assert_eq!(requirement, "bench");
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+
+        let mut conf: TemplateCIConfig =
+            toml::from_str(r#"test_requires = ["miri"]"#).expect("disabled built-in requirement");
+        match conf.validate() {
+            Err(super::Error::DisabledRequirement { name, requirement }) => {
+                assert_eq!(name, "test");
+                assert_eq!(requirement, "miri");
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+
+        let mut conf: TemplateCIConfig = toml::from_str(
+            r#"
+[additional_matrix_entries.integration]
+run = true
+"#,
+        )
+        .expect("entry without a commandline");
+        match conf.validate() {
+            Err(super::Error::MissingCommandline { entry }) => assert_eq!(entry, "integration"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+
+        for commandline in &["make test", "cargo test --test it", "cargo test --lib"] {
+            let mut conf: TemplateCIConfig = toml::from_str(&format!(
+                "[additional_matrix_entries.integration]\ncommandline = {:?}\nparallelism = 2",
+                commandline
+            ))
+            .expect("unsplittable entry");
+            match conf.validate() {
+                Err(super::Error::UnsplittableCommandline { entry }) => {
+                    assert_eq!(entry, "integration")
+                }
+                other => panic!("Expected an error, got {:?}", other),
+            }
+        }
+        let mut conf: TemplateCIConfig = toml::from_str(
+            r#"
+test_commandline = "cargo test --lib"
+test_parallelism = 2
+
+[additional_matrix_entries.integration]
+commandline = "cargo test --release -- --ignored"
+parallelism = 2
+"#,
+        )
+        .expect("unsplittable test job");
+        match conf.validate() {
+            Err(super::Error::UnsplittableCommandline { entry }) => assert_eq!(entry, "test"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        conf.test_commandline = "cargo test --all".to_string();
+        conf.validate().expect("splittable command lines");
+        conf.member_tests.push(MemberTest {
+            name: "foo".to_string(),
+            commandline: "make -C crates/foo test".to_string(),
+            dir: "crates/foo".to_string(),
+            has_lib_targets: false,
+            paths: vec!["crates/foo".to_string()],
+        });
+        match conf.validate() {
+            Err(super::Error::UnsplittableCommandline { entry }) => assert_eq!(entry, "foo"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+
+        let mut conf: TemplateCIConfig = toml::from_str(
+            r#"
+[clippy]
+requires = ["rustfmt"]
+
+[rustfmt]
+requires = ["clippy"]
+"#,
+        )
+        .expect("cyclic requirements");
+        match conf.validate() {
+            Err(super::Error::RequirementCycle { cycle }) => {
+                assert_eq!(cycle, "clippy -> rustfmt -> clippy");
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn member_tests_on_pinned_toolchain() {
+        let member_conf = |toolchain_file| TemplateCIConfig {
+            versions: vec!["stable".to_string()],
+            toolchain_file,
+            toolchain: Some(Toolchain {
+                channel: "1.70.0".to_string(),
+                components: vec![],
+                targets: vec![],
+            }),
+            member_tests: vec![MemberTest {
+                name: "foo".to_string(),
+                commandline: "cargo test -p foo".to_string(),
+                dir: "foo".to_string(),
+                has_lib_targets: true,
+                paths: vec!["foo".to_string()],
+            }],
+            ..Default::default()
+        };
+
+        let conf = member_conf(ToolchainFileMode::Respect);
+        assert_eq!(conf.member_test_versions(), vec![("1.70.0", true)]);
+        let conf = member_conf(ToolchainFileMode::Matrix);
+        assert_eq!(
+            conf.member_test_versions(),
+            vec![("stable", false), ("1.70.0", true)]
+        );
+        let conf = member_conf(ToolchainFileMode::Override);
+        assert_eq!(conf.member_test_versions(), vec![("stable", false)]);
+    }
 }
@@ -4,6 +4,23 @@ macro_rules! define_matrix_entry {
       $version_default:expr,
       $install_default:expr,
       $commandline_default:expr)) => {
+        define_matrix_entry!(
+            $name,
+            (
+                $run_default,
+                $version_default,
+                $install_default,
+                $commandline_default,
+                Vec::<&str>::new()
+            )
+        );
+    };
+    ($name:ident,
+     ($run_default:expr,
+      $version_default:expr,
+      $install_default:expr,
+      $commandline_default:expr,
+      $components_default:expr)) => {
         #[derive(Debug)]
         pub(crate) struct $name(MatrixEntry);
 
@@ -15,14 +32,20 @@ macro_rules! define_matrix_entry {
 
         impl Default for $name {
             fn default() -> Self {
-                let cmdline: Option<String> = $commandline_default.into();
                 $name(MatrixEntry {
                     run: $run_default,
                     run_cron: false,
                     version: String::from($version_default),
                     install_commandline: $install_default.into(),
-                    commandline: cmdline.unwrap_or("/bin/false".to_owned()),
+                    commandline: $commandline_default.into(),
                     timeout: None,
+                    os: vec![],
+                    requires: vec![],
+                    allow_failure: false,
+                    parallelism: None,
+                    tools: vec![],
+                    components: $components_default.into_iter().map(String::from).collect(),
+                    targets: vec![],
                 })
             }
         }
@@ -48,6 +71,25 @@ macro_rules! define_matrix_entry {
                     #[serde(default)]
                     #[serde(with = "humantime_serde")]
                     timeout: Option<Duration>,
+
+                    os: Option<Vec<String>>,
+
+                    #[serde(default)]
+                    requires: Vec<String>,
+
+                    #[serde(default)]
+                    allow_failure: bool,
+
+                    #[serde(default)]
+                    parallelism: Option<u32>,
+
+                    #[serde(default)]
+                    tools: Vec<String>,
+
+                    components: Option<Vec<String>>,
+
+                    #[serde(default)]
+                    targets: Vec<String>,
                 }
                 impl<'a> Default for DeserializationStruct {
                     fn default() -> Self {
@@ -58,6 +100,15 @@ macro_rules! define_matrix_entry {
                             install_commandline: $install_default.into(),
                             commandline: $commandline_default.into(),
                             timeout: None,
+                            os: Some(vec![]),
+                            requires: vec![],
+                            allow_failure: false,
+                            parallelism: None,
+                            tools: vec![],
+                            components: Some(
+                                $components_default.into_iter().map(String::from).collect(),
+                            ),
+                            targets: vec![],
                         }
                     }
                 }
@@ -77,9 +128,18 @@ macro_rules! define_matrix_entry {
                         .or(DeserializationStruct::default().install_commandline),
                     commandline: raw
                         .commandline
-                        .or(DeserializationStruct::default().commandline)
-                        .expect("Matrix entries need a commandline"),
+                        .or(DeserializationStruct::default().commandline),
                     timeout: raw.timeout.or(DeserializationStruct::default().timeout),
+                    os: raw.os.or(DeserializationStruct::default().os).unwrap(),
+                    requires: raw.requires,
+                    allow_failure: raw.allow_failure,
+                    parallelism: raw.parallelism,
+                    tools: raw.tools,
+                    components: raw
+                        .components
+                        .or(DeserializationStruct::default().components)
+                        .unwrap(),
+                    targets: raw.targets,
                 });
                 Ok(res)
             }
@@ -1,6 +1,6 @@
 #![deny(warnings)]
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 #[macro_use]
@@ -9,6 +9,8 @@ mod macros;
 mod bors;
 mod ci;
 mod config;
+mod nightly;
+mod toolchain;
 
 use crate::ci::{circleci::CircleCI, travis::TravisCI, CISystem};
 pub(crate) use crate::config::TemplateCIConfig;
@@ -25,22 +27,112 @@ enum Cmdline {
         cmd: Option<GenerateCommand>,
         #[structopt(long = "manifest", help = "Path to Cargo.toml", parse(from_os_str))]
         cargo_manifest: Option<PathBuf>,
+        #[structopt(
+            long = "write-bors",
+            help = "Create or update bors.toml to wait for the generated status check"
+        )]
+        write_bors: bool,
+        #[structopt(
+            long = "bors-timeout-from-jobs",
+            help = "With --write-bors, set bors.toml's timeout_sec to the sum of all job timeouts"
+        )]
+        bors_timeout_from_jobs: bool,
     },
 }
 
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Debug, Default)]
 enum GenerateCommand {
+    #[default]
     #[structopt(name = "travis", about = "Generate travis-ci configuration")]
     TravisCI,
 
     #[structopt(name = "circleci", about = "Generate circleci configuration")]
     CircleCI,
+
+    #[structopt(
+        name = "bump-nightly",
+        about = "Pin nightly toolchains to a newer date and regenerate the existing CI configuration"
+    )]
+    BumpNightly {
+        #[structopt(
+            long = "date",
+            help = "The date of the nightly to pin, as YYYY-MM-DD. Defaults to yesterday's nightly"
+        )]
+        date: Option<String>,
+    },
+}
+
+/// Writes the CI system's config file, after updating bors.toml if
+/// requested.
+fn generate(
+    sys: &impl CISystem,
+    root: &Path,
+    write_bors: bool,
+    bors_timeout: Option<u64>,
+) -> Result<(), ci::Error> {
+    if write_bors {
+        sys.render_with_bors_config(root, bors_timeout)
+    } else {
+        sys.render_into_config_file(root)
+    }
+}
+
+/// Returns whether the repo at `root` already uses travis and
+/// CircleCI.
+fn used_ci_systems(root: &Path) -> (bool, bool) {
+    (
+        root.join(ci::travis::CONFIG_FILE).is_file(),
+        root.join(ci::circleci::CONFIG_FILE).is_file(),
+    )
 }
 
-impl Default for GenerateCommand {
-    fn default() -> Self {
-        GenerateCommand::TravisCI
+/// Regenerates the config of every CI system that the repo already
+/// uses.
+fn regenerate(
+    conf: TemplateCIConfig,
+    root: &Path,
+    write_bors: bool,
+    bors_timeout: Option<u64>,
+) -> Result<(), ci::Error> {
+    let (uses_travis, uses_circleci) = used_ci_systems(root);
+    let travis = TravisCI::from(conf);
+    if uses_travis {
+        generate(&travis, root, write_bors, bors_timeout)?;
     }
+    if uses_circleci {
+        let circleci = CircleCI::from(travis.into_config());
+        generate(&circleci, root, write_bors, bors_timeout)?;
+    }
+    Ok(())
+}
+
+/// Rewrites the pinned nightly toolchains in the configuration to
+/// the nightly of `date`, or of yesterday.
+fn bump_nightly(
+    manifest: Option<&Path>,
+    date: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let date = date.unwrap_or_else(nightly::latest_date);
+    let (path, table_path) = config::TemplateCIConfig::config_source(manifest)?.table();
+    // Config files live in the repo root, and so does the manifest
+    // that holds the configuration otherwise:
+    let root = path
+        .parent()
+        .expect("Impossible: config file has no parent");
+    if used_ci_systems(root) == (false, false) {
+        let e = nightly::Error::NoCIConfig;
+        eprintln!("Bumping the nightly toolchain failed. {}", e);
+        return Err(e.into());
+    }
+    if let Err(e) = nightly::bump(&path, table_path, &date) {
+        eprintln!(
+            "Bumping the nightly toolchain in {} failed. {}",
+            path.display(),
+            e
+        );
+        return Err(e.into());
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -48,13 +140,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let Cmdline::TemplateCI {
         cmd,
         cargo_manifest,
+        write_bors,
+        bors_timeout_from_jobs,
     } = opts;
+    let cmd = cmd.unwrap_or_default();
+    if let GenerateCommand::BumpNightly { date } = &cmd {
+        bump_nightly(cargo_manifest.as_deref(), date.clone())?;
+    }
 
     let (conf, dest) = config::TemplateCIConfig::merged_configs(cargo_manifest.as_deref())?;
+    let bors_timeout = if write_bors && bors_timeout_from_jobs {
+        conf.total_timeout().map(|timeout| timeout.as_secs())
+    } else {
+        None
+    };
 
-    let res = match cmd.unwrap_or_default() {
-        GenerateCommand::TravisCI => TravisCI::from(conf).render_into_config_file(&dest),
-        GenerateCommand::CircleCI => CircleCI::from(conf).render_into_config_file(&dest),
+    let res = match cmd {
+        GenerateCommand::TravisCI => {
+            generate(&TravisCI::from(conf), &dest, write_bors, bors_timeout)
+        }
+        GenerateCommand::CircleCI => {
+            generate(&CircleCI::from(conf), &dest, write_bors, bors_timeout)
+        }
+        GenerateCommand::BumpNightly { .. } => regenerate(conf, &dest, write_bors, bors_timeout),
     };
     if let Err(e) = res {
         eprintln!("Generating CI config failed. {}", e);
@@ -0,0 +1,232 @@
+use custom_error::custom_error;
+use std::{
+    fs::{read_to_string, write},
+    io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+custom_error! {pub Error
+               IO{source: io::Error} = "could not read the template-ci configuration",
+               TomlEdit{source: toml_edit::TomlError} = "could not parse the template-ci configuration for editing",
+               BadDate{date: String} = "Bad nightly date {date:?}: Use YYYY-MM-DD",
+               NoPinnedNightly = "The template-ci configuration does not pin any nightly toolchain",
+               NoCIConfig = "The repo has no travis or CircleCI config to regenerate",
+}
+
+/// The channel that pinned nightly toolchains belong to.
+pub(crate) const CHANNEL: &str = "nightly";
+
+/// Returns the date of a nightly toolchain that is pinned like
+/// `nightly-2020-01-01`, or None for any other version.
+pub(crate) fn pinned_date(version: &str) -> Option<&str> {
+    let date = version.strip_prefix(CHANNEL)?.strip_prefix('-')?;
+    if is_date(date) {
+        Some(date)
+    } else {
+        None
+    }
+}
+
+/// Returns true if `date` is a valid date in the YYYY-MM-DD format.
+fn is_date(date: &str) -> bool {
+    let parts: Vec<&str> = date.split('-').collect();
+    let well_formed = parts.len() == 3
+        && parts
+            .iter()
+            .zip(&[4, 2, 2])
+            .all(|(part, len)| part.len() == *len && part.bytes().all(|b| b.is_ascii_digit()));
+    if !well_formed {
+        return false;
+    }
+    let [year, month, day] = [parts[0], parts[1], parts[2]].map(|part| {
+        part.parse::<u32>()
+            .expect("Impossible: the date parts are digits")
+    });
+    (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day)
+}
+
+/// Returns the number of days in a month of the gregorian calendar.
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Returns the date of yesterday's nightly in UTC, the most recent
+/// one that has certainly been published.
+pub(crate) fn latest_date() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("The system clock is set before 1970");
+    civil_date(now.as_secs() as i64 / (24 * 60 * 60) - 1)
+}
+
+/// Converts a number of days since 1970-01-01 into a date, see
+/// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_date(days: i64) -> String {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Rewrites every pinned nightly version in the template-ci
+/// configuration to the nightly of `date`. The configuration is the
+/// table at `table_path` in the TOML file at `path`; everything else
+/// in the file stays as it is.
+pub(crate) fn bump(path: &Path, table_path: &[&str], date: &str) -> Result<(), Error> {
+    if !is_date(date) {
+        return Err(Error::BadDate {
+            date: date.to_string(),
+        });
+    }
+    let mut doc: toml_edit::Document = read_to_string(path)?.parse()?;
+    let mut table = Some(doc.as_item_mut());
+    for key in table_path {
+        table = table.and_then(|table| table.get_mut(key));
+    }
+    let version = format!("{}-{}", CHANNEL, date);
+    let bumped = table.map_or(0, |table| bump_item(table, &version));
+    if bumped == 0 {
+        return Err(Error::NoPinnedNightly);
+    }
+    write(path, doc.to_string())?;
+    Ok(())
+}
+
+/// Sets all pinned nightly versions in `item` to `version` and
+/// returns how many there were.
+fn bump_item(item: &mut toml_edit::Item, version: &str) -> usize {
+    match item {
+        toml_edit::Item::Value(value) => bump_value(value, version),
+        toml_edit::Item::Table(table) => table
+            .iter_mut()
+            .map(|(_, item)| bump_item(item, version))
+            .sum(),
+        toml_edit::Item::ArrayOfTables(tables) => tables
+            .iter_mut()
+            .flat_map(|table| table.iter_mut())
+            .map(|(_, item)| bump_item(item, version))
+            .sum(),
+        toml_edit::Item::None => 0,
+    }
+}
+
+fn bump_value(value: &mut toml_edit::Value, version: &str) -> usize {
+    match value {
+        toml_edit::Value::String(string) if pinned_date(string.value()).is_some() => {
+            let mut bumped = toml_edit::Formatted::new(version.to_string());
+            *bumped.decor_mut() = string.decor().clone();
+            *string = bumped;
+            1
+        }
+        toml_edit::Value::Array(array) => array
+            .iter_mut()
+            .map(|value| bump_value(value, version))
+            .sum(),
+        toml_edit::Value::InlineTable(table) => table
+            .iter_mut()
+            .map(|(_, value)| bump_value(value, version))
+            .sum(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned_dates() {
+        assert_eq!(pinned_date("nightly-2020-01-31"), Some("2020-01-31"));
+        assert_eq!(pinned_date("nightly"), None);
+        assert_eq!(pinned_date("nightly-x86_64-unknown-linux-gnu"), None);
+        assert_eq!(pinned_date("nightly-2020-1-31"), None);
+        assert_eq!(pinned_date("nightly-2020-13-01"), None);
+        assert_eq!(pinned_date("nightly-2020-00-10"), None);
+        assert_eq!(pinned_date("nightly-2020-04-31"), None);
+        assert_eq!(pinned_date("nightly-2020-01-00"), None);
+        assert_eq!(pinned_date("nightly-2020-02-29"), Some("2020-02-29"));
+        assert_eq!(pinned_date("nightly-2100-02-29"), None);
+        assert_eq!(pinned_date("stable"), None);
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_date(0), "1970-01-01");
+        assert_eq!(civil_date(11_016), "2000-02-29");
+        assert_eq!(civil_date(20_454), "2026-01-01");
+        assert!(is_date(&latest_date()));
+    }
+
+    #[test]
+    fn bump_manifest() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("Cargo.toml");
+        write(
+            &path,
+            r#"[package]
+name = "nightly-2020-01-01"
+
+[package.metadata.template_ci]
+# Keep this comment
+versions = ["stable", "nightly-2020-01-01" ]
+
+[package.metadata.template_ci.bench]
+version = 'nightly-2020-01-01'
+"#,
+        )?;
+        bump(&path, &["package", "metadata", "template_ci"], "2020-02-02")?;
+        assert_eq!(
+            read_to_string(&path)?,
+            r#"[package]
+name = "nightly-2020-01-01"
+
+[package.metadata.template_ci]
+# Keep this comment
+versions = ["stable", "nightly-2020-02-02" ]
+
+[package.metadata.template_ci.bench]
+version = "nightly-2020-02-02"
+"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bump_errors() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("template-ci.toml");
+        write(&path, "versions = [\"stable\", \"nightly\"]\n")?;
+        match bump(&path, &[], "2020-02-02") {
+            Err(Error::NoPinnedNightly) => {}
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        match bump(&path, &[], "2020-2-2") {
+            Err(Error::BadDate { date }) => assert_eq!(date, "2020-2-2"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        match bump(&path, &[], "2020-02-30") {
+            Err(Error::BadDate { date }) => assert_eq!(date, "2020-02-30"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use custom_error::custom_error;
+use serde_derive::Deserialize;
+use std::{fs::read_to_string, io, path::Path};
+
+/// How generated test jobs treat a toolchain file in the repo root.
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ToolchainFileMode {
+    /// Remove the toolchain file and test on each configured version.
+    #[default]
+    Override,
+
+    /// Test on each configured version, and additionally on the
+    /// pinned toolchain.
+    Matrix,
+
+    /// Only test on the pinned toolchain.
+    Respect,
+}
+
+/// A toolchain pinned by a `rust-toolchain` or `rust-toolchain.toml` file.
+#[derive(PartialEq, Debug, Deserialize)]
+pub(crate) struct Toolchain {
+    pub(crate) channel: String,
+
+    #[serde(default)]
+    pub(crate) components: Vec<String>,
+
+    #[serde(default)]
+    pub(crate) targets: Vec<String>,
+}
+
+impl Toolchain {
+    /// Returns the rustup command line that installs the pinned
+    /// toolchain along with its components and targets.
+    pub(crate) fn install_commandline(&self) -> String {
+        let mut cmdline = format!("rustup toolchain install {}", self.channel);
+        for component in &self.components {
+            cmdline.push_str(" --component ");
+            cmdline.push_str(component);
+        }
+        for target in &self.targets {
+            cmdline.push_str(" --target ");
+            cmdline.push_str(target);
+        }
+        cmdline
+    }
+}
+
+custom_error! {pub Error
+               IO{source: io::Error} = "could not read rust-toolchain file",
+               Toml{source: toml::de::Error} = "could not parse rust-toolchain file as TOML",
+}
+
+/// Names of the toolchain files that rustup looks at, in the order of
+/// rustup's precedence.
+pub(crate) const FILE_NAMES: &[&str] = &["rust-toolchain", "rust-toolchain.toml"];
+
+/// Reads the toolchain file in the repo root, if one exists.
+pub(crate) fn detect(root: &Path) -> Result<Option<Toolchain>, Error> {
+    #[derive(Deserialize)]
+    struct ToolchainFile {
+        toolchain: Toolchain,
+    }
+
+    for name in FILE_NAMES {
+        let path = root.join(name);
+        if !path.is_file() {
+            continue;
+        }
+        let contents = read_to_string(&path)?;
+        let trimmed = contents.trim();
+        // The legacy file format is a single line naming the channel:
+        if *name == "rust-toolchain" && !trimmed.contains('\n') && !trimmed.contains('=') {
+            return Ok(Some(Toolchain {
+                channel: trimmed.to_string(),
+                components: vec![],
+                targets: vec![],
+            }));
+        }
+        let file: ToolchainFile = toml::from_str(&contents)?;
+        return Ok(Some(file.toolchain));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn no_toolchain_file() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        assert_eq!(detect(tmp.path())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_toolchain_file() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        File::create(tmp.path().join("rust-toolchain"))?.write_all(b"nightly-2020-01-01\n")?;
+        let toolchain = detect(tmp.path())?.expect("should find a toolchain");
+        assert_eq!(toolchain.channel, "nightly-2020-01-01");
+        assert_eq!(
+            toolchain.install_commandline(),
+            "rustup toolchain install nightly-2020-01-01"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn toml_toolchain_file() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        File::create(tmp.path().join("rust-toolchain.toml"))?.write_all(
+            br#"
+[toolchain]
+channel = "1.40.0"
+components = ["rustfmt", "clippy"]
+targets = ["wasm32-unknown-unknown"]
+"#,
+        )?;
+        let toolchain = detect(tmp.path())?.expect("should find a toolchain");
+        assert_eq!(
+            toolchain.install_commandline(),
+            "rustup toolchain install 1.40.0 --component rustfmt --component clippy --target wasm32-unknown-unknown"
+        );
+        Ok(())
+    }
+}